@@ -0,0 +1,95 @@
+use {Borrow, State};
+use mutex::Mutex;
+use unsize::Inner;
+use waiters::Waiters;
+
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::marker::PhantomData;
+
+/// Configures and creates a `Borrow`.
+///
+/// Returned by `Borrow::builder`.
+pub struct Builder<T> {
+    policy: Policy,
+    _p: PhantomData<fn(T)>,
+}
+
+/// Determines which waiting task acquires a released borrow.
+///
+/// Only tasks waiting on the borrow are affected by the policy. When the value
+/// is not contended, borrows are granted in the same way regardless of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Shared borrows are granted whenever the value is not exclusively
+    /// borrowed, even while other tasks wait for an exclusive borrow. All
+    /// waiting tasks are notified when the borrow is released and race to
+    /// acquire it.
+    ///
+    /// This maximizes concurrency, but a continuous stream of readers can
+    /// starve writers. This is the default policy.
+    ReaderPriority,
+
+    /// No new shared borrow is granted while a task is waiting for an
+    /// exclusive borrow, so readers cannot starve writers. Tasks waiting for a
+    /// shared borrow are notified once no writer is waiting anymore.
+    ///
+    /// A waiting `Borrow` handle keeps holding back readers until it acquires
+    /// the borrow or is dropped. A pending `UpgradeableGuard::upgrade` holds
+    /// back readers in the same way.
+    WriterPriority,
+
+    /// Borrows are handed out to waiting tasks in the order in which they
+    /// started waiting. Consecutive tasks waiting for a shared borrow acquire
+    /// it together.
+    ///
+    /// Once a task is waiting on the borrow, `try_borrow` calls and tasks that
+    /// start waiting later will not be able to acquire the borrow until the
+    /// waiting task does. A waiting `Borrow` handle keeps its place in the
+    /// queue until it acquires the borrow or is dropped. A pending
+    /// `UpgradeableGuard::upgrade` goes to the front of the queue, as it
+    /// already excludes other writers.
+    Fifo,
+}
+
+impl<T> Builder<T> {
+    pub(crate) fn new() -> Builder<T> {
+        Builder {
+            policy: Policy::default(),
+            _p: PhantomData,
+        }
+    }
+
+    /// Set the policy used to hand out the borrow to waiting tasks.
+    ///
+    /// Defaults to `Policy::ReaderPriority`.
+    pub fn policy(mut self, policy: Policy) -> Builder<T> {
+        self.policy = policy;
+        self
+    }
+
+    /// Create the `Borrow` containing `value`.
+    pub fn build(self, value: T) -> Borrow<T> {
+        let waiters = Arc::new(Mutex::new(Waiters::new(self.policy)));
+
+        Borrow::from_inner(Arc::new(Inner {
+            state: State::new::<T>(waiters, self.policy),
+            value: UnsafeCell::new(value),
+        }))
+    }
+}
+
+impl<T> fmt::Debug for Builder<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Builder")
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy::ReaderPriority
+    }
+}
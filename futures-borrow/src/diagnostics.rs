@@ -0,0 +1,218 @@
+//! Tracking of the outstanding borrows of a value.
+//!
+//! Holders are only recorded when the `diagnostics` feature is enabled,
+//! otherwise all of the types in this module are zero-sized and the
+//! operations are no-ops.
+
+use Access;
+#[cfg(feature = "diagnostics")]
+use mutex::{Mutex, MutexGuard};
+
+#[cfg(feature = "diagnostics")]
+use std::{fmt, thread};
+#[cfg(feature = "diagnostics")]
+use std::panic::Location;
+#[cfg(feature = "diagnostics")]
+use std::string::String;
+#[cfg(feature = "diagnostics")]
+use std::vec::Vec;
+#[cfg(feature = "diagnostics")]
+use std::time::{Duration, Instant};
+
+/// The location at which a borrow was requested.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Site {
+    #[cfg(feature = "diagnostics")]
+    location: &'static Location<'static>,
+}
+
+/// Identifies the record of a borrow holder.
+#[derive(Debug)]
+pub(crate) struct HolderKey {
+    #[cfg(feature = "diagnostics")]
+    key: usize,
+}
+
+/// The records of all outstanding borrows of a value.
+#[derive(Debug)]
+pub(crate) struct Holders {
+    #[cfg(feature = "diagnostics")]
+    entries: Mutex<Vec<Option<Holder>>>,
+}
+
+/// Information about an outstanding borrow.
+///
+/// Returned by `Borrow::holders` and `Borrow::long_held`.
+#[cfg(feature = "diagnostics")]
+#[derive(Clone)]
+pub struct Holder {
+    location: &'static Location<'static>,
+    thread_id: thread::ThreadId,
+    thread_name: Option<String>,
+    acquired: Instant,
+    access: Access,
+}
+
+impl Site {
+    /// Returns the location of the caller of the enclosing `#[track_caller]`
+    /// function.
+    #[track_caller]
+    pub fn caller() -> Site {
+        Site {
+            #[cfg(feature = "diagnostics")]
+            location: Location::caller(),
+        }
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+impl Holders {
+    pub fn new() -> Holders {
+        Holders {
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Record a new holder acquired from the current thread.
+    pub fn insert(&self, site: Site, access: Access) -> HolderKey {
+        let thread = thread::current();
+
+        let holder = Holder {
+            location: site.location,
+            thread_id: thread.id(),
+            thread_name: thread.name().map(String::from),
+            acquired: Instant::now(),
+            access,
+        };
+
+        self.insert_holder(holder)
+    }
+
+    /// Record a new holder sharing the record identified by `key`.
+    pub fn duplicate(&self, key: &HolderKey) -> HolderKey {
+        let holder = self.entries()[key.key].clone()
+            .expect("invalid holder key");
+
+        self.insert_holder(holder)
+    }
+
+    /// Update the kind of borrow held.
+    pub fn set_access(&self, key: &HolderKey, access: Access) {
+        if let Some(ref mut holder) = self.entries()[key.key] {
+            holder.access = access;
+        }
+    }
+
+    pub fn remove(&self, key: &HolderKey) {
+        self.entries()[key.key] = None;
+    }
+
+    /// Returns the records of all outstanding borrows.
+    pub fn snapshot(&self) -> Vec<Holder> {
+        self.entries().iter()
+            .filter_map(|holder| holder.clone())
+            .collect()
+    }
+
+    fn insert_holder(&self, holder: Holder) -> HolderKey {
+        let mut entries = self.entries();
+
+        let key = match entries.iter().position(Option::is_none) {
+            Some(key) => {
+                entries[key] = Some(holder);
+                key
+            }
+            None => {
+                entries.push(Some(holder));
+                entries.len() - 1
+            }
+        };
+
+        HolderKey { key }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, Vec<Option<Holder>>> {
+        self.entries.lock()
+    }
+}
+
+#[cfg(not(feature = "diagnostics"))]
+impl Holders {
+    pub fn new() -> Holders {
+        Holders {}
+    }
+
+    pub fn insert(&self, _: Site, _: Access) -> HolderKey {
+        HolderKey {}
+    }
+
+    pub fn duplicate(&self, _: &HolderKey) -> HolderKey {
+        HolderKey {}
+    }
+
+    pub fn set_access(&self, _: &HolderKey, _: Access) {
+    }
+
+    pub fn remove(&self, _: &HolderKey) {
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+impl Holder {
+    /// Returns the location at which the borrow was requested.
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+
+    /// Returns the id of the thread that acquired the borrow.
+    pub fn thread_id(&self) -> thread::ThreadId {
+        self.thread_id
+    }
+
+    /// Returns the name of the thread that acquired the borrow, if it has one.
+    pub fn thread_name(&self) -> Option<&str> {
+        self.thread_name.as_ref().map(|name| &name[..])
+    }
+
+    /// Returns the instant at which the borrow was acquired.
+    pub fn acquired(&self) -> Instant {
+        self.acquired
+    }
+
+    /// Returns how long the borrow has been held.
+    pub fn held_for(&self) -> Duration {
+        self.acquired.elapsed()
+    }
+
+    /// Returns `true` if the borrow is exclusive.
+    pub fn is_exclusive(&self) -> bool {
+        self.access == Access::Exclusive
+    }
+
+    /// Returns `true` if the borrow is shared.
+    pub fn is_shared(&self) -> bool {
+        self.access == Access::Shared
+    }
+
+    /// Returns `true` if the borrow is upgradeable.
+    pub fn is_upgradeable(&self) -> bool {
+        self.access == Access::Upgradeable
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+impl fmt::Debug for Holder {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let thread = match self.thread_name {
+            Some(ref name) => name.clone(),
+            None => ::std::format!("{:?}", self.thread_id),
+        };
+
+        fmt.debug_struct("Holder")
+            .field("access", &self.access)
+            .field("location", &format_args!("{}", self.location))
+            .field("thread", &thread)
+            .field("held_for", &self.held_for())
+            .finish()
+    }
+}
@@ -7,18 +7,125 @@
 //!
 //! `Borrow` provides runtime checked borrowing, similar to `RefCell`, however
 //! `Borrow` also provides `Future` task notifications when borrows are dropped.
+//!
+//! Borrows are either exclusive (`BorrowGuard`) or shared (`SharedGuard`). Any
+//! number of shared borrows may be outstanding at the same time, but an
+//! exclusive borrow can only be acquired once all shared borrows have been
+//! released.
+//!
+//! An exclusive borrow can be atomically downgraded to a shared borrow with
+//! `BorrowGuard::downgrade`. Code that only sometimes needs to mutate the value
+//! can instead acquire an upgradeable borrow (`UpgradeableGuard`). It coexists
+//! with shared borrows, but only one upgradeable borrow may be outstanding at a
+//! time, which allows it to later be promoted to an exclusive borrow with
+//! `UpgradeableGuard::upgrade` without another writer slipping in.
+//!
+//! Every task waiting on a borrow is tracked and notified once the borrow is
+//! released. By default, waiters race to acquire the released borrow and
+//! shared borrows are granted as long as the value is not exclusively
+//! borrowed. A different `Policy` can be selected with `Borrow::builder`:
+//! `Policy::WriterPriority` holds back shared borrows while a task waits for an
+//! exclusive borrow, and `Policy::Fifo` (also available as `Borrow::new_fair`)
+//! hands out the borrow in the order that waiters arrived, preventing a busy
+//! task from starving others.
+//! Waiting can be bounded using `BorrowFuture::timeout` or
+//! `BorrowFuture::cancel_on`.
+//!
+//! A task can also wait for the value to satisfy a condition using
+//! `Borrow::borrow_when`. The borrow is released while the condition does not
+//! hold, and the task is notified each time an exclusive borrow that mutated
+//! the value is released. Observers that do not need to borrow the value can
+//! instead track its modification version with `Borrow::version` and
+//! `Borrow::poll_changed`, making the same cell serve as a change notifier.
+//!
+//! `BorrowPool` hands out exclusive borrows to any free item of a pool of
+//! values, notifying waiting tasks as soon as one of the items is released.
+//! `BorrowMap` provides exclusive borrows per key, creating and evicting
+//! entries as needed. `Semaphore` hands out weighted permits, for example to
+//! limit the number of concurrent requests to a service.
+//!
+//! # Poisoning
+//!
+//! If an exclusive borrow is released while the thread is panicking, the value
+//! is considered poisoned: the panic may have left it in an inconsistent state.
+//! Subsequent `poll_borrow` and `try_borrow` calls fail with an error reporting
+//! the poisoning. Similar to `std::sync::Mutex`, the value can still be
+//! accessed using `poll_borrow_ignore_poison` or `try_borrow_ignore_poison` and,
+//! once the value is known to be consistent, the flag can be reset with
+//! `clear_poison`.
+//!
+//! # `no_std` support
+//!
+//! The `std` feature is enabled by default. Without it, the crate only depends
+//! on `core` and `alloc`: waiter lists are protected by a spin lock and
+//! `BorrowMap` is not available. As panics cannot be detected, values are
+//! never poisoned unless a hook reporting whether the current thread is
+//! panicking is installed with `set_panicking_hook`, which is ignored when
+//! the `std` feature is enabled. The `diagnostics` and `stats` features
+//! require `std`.
+//!
+//! # Diagnostics
+//!
+//! With the `diagnostics` feature enabled, every outstanding borrow records
+//! where and on which thread it was acquired, as well as when. This is
+//! reported by the `Debug` implementation of `Borrow` and can be queried with
+//! `Borrow::holders`. `Borrow::long_held` returns the borrows held for longer
+//! than a threshold, which can be used to build a watchdog.
+//!
+//! With the `stats` feature enabled, contention metrics are collected for each
+//! value and can be retrieved with `Borrow::stats`.
 
+#![no_std]
+
+#[macro_use]
 extern crate futures;
 
-use futures::{Poll, Async};
-use futures::task::AtomicTask;
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+mod builder;
+mod diagnostics;
+#[cfg(feature = "std")]
+mod map;
+mod mutex;
+mod panicking;
+mod pool;
+mod semaphore;
+mod stats;
+mod waiters;
+
+pub use builder::{Builder, Policy};
+#[cfg(feature = "diagnostics")]
+pub use diagnostics::Holder;
+#[cfg(feature = "std")]
+pub use map::{BorrowMap, BorrowMapFuture};
+pub use panicking::set_panicking_hook;
+pub use pool::{BorrowPool, AcquireFuture, RemoveFuture};
+pub use semaphore::{Semaphore, Permit, AcquirePermits};
+#[cfg(feature = "stats")]
+pub use stats::BorrowStats;
+
+use diagnostics::{Holders, HolderKey, Site};
+use mutex::{Mutex, MutexGuard};
+use stats::{Stats, Timestamp};
+use unsize::Inner;
+use waiters::Waiters;
 
-use std::{fmt, ops, thread};
-use std::any::Any;
-use std::cell::UnsafeCell;
-use std::sync::Arc;
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::Ordering::{Acquire, Release};
+use futures::{Future, IntoFuture, Poll, Async, Stream, Sink, StartSend};
+use futures::task;
+
+use alloc::sync::{Arc, Weak};
+#[cfg(feature = "diagnostics")]
+use alloc::vec::Vec;
+use core::{fmt, mem, ops, ptr};
+use core::marker::PhantomData;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::{Acquire, Release, AcqRel, Relaxed};
+#[cfg(feature = "std")]
+use std::error::Error;
+#[cfg(feature = "diagnostics")]
+use std::time::Duration;
 
 /// A mutable memory location with future-aware dynamically checked borrow
 /// rules.
@@ -27,32 +134,181 @@ use std::sync::atomic::Ordering::{Acquire, Release};
 /// stored in stable memory. To do this, `Borrow` internally creates an `Arc` to
 /// store the data.
 ///
+/// `Borrow` handles are cheap to clone. All clones refer to the same value and
+/// each clone tracks its own waiting task, so clones may be moved to different
+/// tasks in order to share the value between them.
+///
 /// See crate level documentation for more details.
-pub struct Borrow<T> {
+pub struct Borrow<T: ?Sized> {
     /// The borrow state.
     ///
     /// The state is stored in an `Arc` in order to ensure that it does not move
     /// to a different memory location while it is being borrowed.
     inner: Arc<Inner<T>>,
+
+    /// Key identifying this handle's entry in the waiter list.
+    key: usize,
 }
 
 /// Holds a borrowed value obtained from `Borrow`.
 ///
 /// When this value is dropped, the borrow is released, notiying any pending
 /// tasks.
-pub struct BorrowGuard<T> {
+///
+/// Guards keep the value alive, so anything it borrows must outlive them,
+/// even once all `Borrow` handles have been dropped:
+///
+/// ```compile_fail,E0597
+/// # use futures_borrow::Borrow;
+/// struct Print<'a>(&'a String);
+///
+/// impl<'a> Drop for Print<'a> {
+///     fn drop(&mut self) {
+///         println!("{}", self.0);
+///     }
+/// }
+///
+/// let guard;
+/// let s = String::from("hello");
+///
+/// {
+///     let borrow = Borrow::new(Print(&s));
+///     guard = borrow.try_borrow().unwrap();
+/// }
+/// ```
+pub struct BorrowGuard<T: ?Sized> {
     /// The borrowed ref. This could be a pointer to an inner field of the `T`
     /// stored by `Borrow`.
     value_ptr: *mut T,
 
     /// Borrowed state
     handle: BorrowHandle,
+
+    /// The guard owns a reference to the value, which may drop it.
+    _p: PhantomData<T>,
+}
+
+/// Holds a shared borrow of a value obtained from `Borrow`.
+///
+/// Any number of `SharedGuard` values may exist at the same time. When the
+/// last one is dropped, the borrow is released, notifying any pending tasks.
+pub struct SharedGuard<T: ?Sized> {
+    /// The borrowed ref.
+    value_ptr: *const T,
+
+    /// Borrowed state
+    handle: BorrowHandle,
+
+    /// The guard owns a reference to the value, which may drop it.
+    _p: PhantomData<T>,
+}
+
+/// Holds an upgradeable borrow of a value obtained from `Borrow`.
+///
+/// An upgradeable borrow provides shared access to the value and may coexist
+/// with any number of `SharedGuard` values, but not with another upgradeable
+/// or exclusive borrow. It can be promoted to a `BorrowGuard` using
+/// `UpgradeableGuard::upgrade`.
+pub struct UpgradeableGuard<T: ?Sized> {
+    /// The borrowed ref.
+    value_ptr: *mut T,
+
+    /// Borrowed state
+    handle: BorrowHandle,
+
+    /// The guard owns a reference to the value, which may drop it.
+    _p: PhantomData<T>,
+}
+
+/// A weak handle to a `Borrow` value.
+///
+/// A `WeakBorrow` does not keep the value alive. It is obtained with
+/// `Borrow::downgrade` and can be upgraded back to a `Borrow` as long as
+/// another `Borrow` handle or outstanding guard still refers to the value.
+pub struct WeakBorrow<T: ?Sized> {
+    inner: Weak<Inner<T>>,
+}
+
+/// Future that resolves to a `BorrowGuard` once the value can be borrowed.
+///
+/// Returned by `Borrow::borrow`.
+pub struct BorrowFuture<T: ?Sized> {
+    /// Handle to the borrowed value
+    inner: Arc<Inner<T>>,
+
+    /// Key identifying the future's entry in the waiter list.
+    key: usize,
+
+    /// Where the borrow was requested
+    site: Site,
+}
+
+/// Future that resolves to a `BorrowGuard` once all shared borrows have been
+/// released.
+///
+/// Returned by `UpgradeableGuard::upgrade`.
+pub struct Upgrade<T: ?Sized> {
+    /// The borrow being upgraded. `None` once the future has completed.
+    guard: Option<UpgradeableGuard<T>>,
+
+    /// Key identifying the future's entry in the waiter list.
+    key: usize,
+
+    _p: PhantomData<T>,
+}
+
+/// Future that resolves to a `BorrowGuard` unless a signal completes first.
+///
+/// Returned by `BorrowFuture::timeout` and `BorrowFuture::cancel_on`.
+pub struct BorrowUntil<T: ?Sized, F> {
+    /// The borrow being waited on
+    future: BorrowFuture<T>,
+
+    /// Completes once the borrow should stop waiting.
+    signal: F,
+
+    /// The error reported when `signal` completes first.
+    kind: Kind,
+}
+
+/// Future that resolves to a `BorrowGuard` once a predicate holds for the
+/// value.
+///
+/// Returned by `Borrow::borrow_when`.
+pub struct BorrowWhen<T: ?Sized, F> {
+    /// Holds the handle and waiter entry used to borrow the value.
+    future: BorrowFuture<T>,
+
+    /// The condition to wait for
+    pred: F,
+}
+
+/// Future that borrows a value and runs a future with the guard.
+///
+/// Returned by `Borrow::with_borrow`.
+pub struct WithBorrow<T: ?Sized, F, U> {
+    /// The borrow being waited on
+    future: BorrowFuture<T>,
+
+    /// Creates the future run with the guard. `None` once it has been called.
+    f: Option<F>,
+
+    /// The future run with the guard, once the borrow is acquired.
+    running: Option<U>,
+}
+
+/// Future that resolves to the value once all outstanding borrows have been
+/// released.
+///
+/// Returned by `Borrow::unwrap`.
+pub struct Unwrap<T> {
+    borrow: Option<Borrow<T>>,
 }
 
 /// Error produced by a failed `poll_borrow` call.
 #[derive(Debug)]
 pub struct BorrowError {
-    _priv: (),
+    kind: Kind,
 }
 
 /// Error produced by a failed `try_borrow` call.
@@ -61,58 +317,191 @@ pub struct TryBorrowError {
     is_poisoned: bool,
 }
 
-struct Inner<T> {
-    /// The value that can be valued
-    value: UnsafeCell<T>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Poisoned,
+    TimedOut,
+    Canceled,
+}
+
+mod unsize {
+    use State;
+
+    use core::cell::UnsafeCell;
+
+    /// Shared storage for a `Borrow` value.
+    ///
+    /// This type is only public in order to be used with `Borrow::unsize`, it
+    /// cannot be named outside of the crate.
+    #[repr(C)]
+    pub struct Inner<T: ?Sized> {
+        /// Borrow state. This must be the first field, see `clone_ref`.
+        pub(crate) state: State,
 
-    /// Borrow state
-    state: State,
+        /// The value that can be borrowed
+        pub(crate) value: UnsafeCell<T>,
+    }
 }
 
+/// A type-erased borrow.
+///
+/// The handle owns a reference to the `Arc` holding the value, preventing it
+/// from being dropped. The reference count is managed through the functions
+/// stored in `State`, so the handle does not need to know the type of the
+/// value.
 struct BorrowHandle {
     /// The borrow state
     state_ptr: *const State,
 
-    /// Holds a handle to the Arc, which prevents it from being dropped.
-    _inner: Arc<Any>,
+    /// The kind of borrow represented by the handle.
+    access: Access,
+
+    /// Identifies the handle's record in the state's holders.
+    holder: HolderKey,
+
+    /// When the borrow was acquired, only tracked with the `stats` feature.
+    acquired: Timestamp,
+
+    /// `true` if the value was mutably accessed through the handle.
+    mutated: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Exclusive,
+    Shared,
+    Upgradeable,
 }
 
 struct State {
     /// Tracks if the value is currently borrowed or poisoned.
+    ///
+    /// The lowest bit is set while the value is exclusively borrowed, the
+    /// second bit is set once the value is poisoned and the third bit is set
+    /// while an upgradeable borrow is outstanding. The remaining bits track
+    /// the number of outstanding shared borrows.
     borrowed: AtomicUsize,
 
-    /// The task to notify once the borrow is released
-    task: AtomicTask,
+    /// Determines which waiters may acquire the borrow.
+    policy: Policy,
+
+    /// Number of additional handles to the exclusive borrow, created by
+    /// splitting a `BorrowGuard`.
+    split: AtomicUsize,
+
+    /// Tasks to notify once the borrow is released. The list is shared by all
+    /// items of a `BorrowPool`.
+    waiters: Arc<Mutex<Waiters>>,
+
+    /// Increments the reference count of the `Arc` holding the state.
+    clone_ref: unsafe fn(*const State),
+
+    /// Decrements the reference count of the `Arc` holding the state,
+    /// dropping the value if this was the last reference.
+    drop_ref: unsafe fn(*const State),
+
+    /// Outstanding borrows, only tracked with the `diagnostics` feature.
+    holders: Holders,
+
+    /// Contention metrics, only collected with the `stats` feature.
+    stats: Stats,
+
+    /// Incremented each time the value is released after being mutated.
+    version: AtomicUsize,
+
+    /// Number of `Borrow` and `BorrowFuture` values referring to the state.
+    /// Unlike the `Arc` reference count, guards are not included.
+    handles: AtomicUsize,
 }
 
 const UNUSED: usize = 0;
 const BORROWED: usize = 1;
 const POISONED: usize = 2;
+const UPGRADEABLE: usize = 4;
+
+/// A single shared borrow.
+const SHARED: usize = 8;
+
+/// Mask covering the shared borrow count.
+const SHARED_MASK: usize = !(SHARED - 1);
+
+/// Mask covering all outstanding borrows.
+const BORROWED_MASK: usize = !POISONED;
 
 // ===== impl Borrow =====
 
-impl<T: 'static> Borrow<T> {
+impl<T> Borrow<T> {
     /// Create a new `Borrow` containing `value`.
     pub fn new(value: T) -> Borrow<T> {
-        Borrow {
-            inner: Arc::new(Inner {
-                value: UnsafeCell::new(value),
-                state: State {
-                    borrowed: AtomicUsize::new(UNUSED),
-                    task: AtomicTask::new(),
-                },
-            }),
+        Borrow::builder().build(value)
+    }
+
+    /// Create a new `Borrow` containing `value` that hands out borrows to
+    /// waiting tasks in FIFO order.
+    ///
+    /// This is equivalent to building the `Borrow` with `Policy::Fifo`.
+    pub fn new_fair(value: T) -> Borrow<T> {
+        Borrow::builder().policy(Policy::Fifo).build(value)
+    }
+
+    /// Returns a builder that can be used to configure a `Borrow`, for
+    /// example to select the policy with which waiting tasks acquire it.
+    pub fn builder() -> Builder<T> {
+        Builder::new()
+    }
+
+    /// Consumes the `Borrow`, returning the value.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if other `Borrow` handles to the value exist or if
+    /// the value is currently borrowed. Use `try_unwrap` to handle this case.
+    pub fn into_inner(self) -> T {
+        match self.try_unwrap() {
+            Ok(value) => value,
+            Err(_) => panic!("value is still shared"),
+        }
+    }
+
+    /// Consumes the `Borrow`, returning the value if this is the only handle to
+    /// it.
+    ///
+    /// Otherwise, the `Borrow` is returned back. Guards obtained from the
+    /// `Borrow` count as handles, `unwrap` can be used to wait for them to be
+    /// released.
+    pub fn try_unwrap(self) -> Result<T, Borrow<T>> {
+        let inner = self.into_arc();
+
+        match Arc::try_unwrap(inner) {
+            Ok(inner) => Ok(inner.value.into_inner()),
+            Err(inner) => Err(Borrow::from_inner(inner)),
+        }
+    }
+
+    /// Returns a future that consumes the `Borrow`, resolving to the value once
+    /// all outstanding borrows have been released.
+    ///
+    /// If other `Borrow` handles to the value still exist at that point, the
+    /// future fails, handing the `Borrow` back.
+    pub fn unwrap(self) -> Unwrap<T> {
+        Unwrap {
+            borrow: Some(self),
         }
     }
+}
+
+impl<T: ?Sized> Borrow<T> {
 
     /// Returns `true` if the value is not already borrowed.
     pub fn is_ready(&self) -> bool {
-        match self.inner.state.borrowed.load(Acquire) {
-            UNUSED => true,
-            BORROWED => false,
-            POISONED => true,
-            _ => unreachable!(),
-        }
+        let curr = self.inner.state.borrowed.load(Acquire);
+        curr & BORROWED_MASK == 0
+    }
+
+    /// Returns `true` if the value is not exclusively borrowed.
+    pub fn is_ready_shared(&self) -> bool {
+        let curr = self.inner.state.borrowed.load(Acquire);
+        curr & BORROWED == 0
     }
 
     /// Returns `Ready` when the value is not already borrowed.
@@ -121,88 +510,315 @@ impl<T: 'static> Borrow<T> {
     /// is guaranteed to succeed. When `NotReady` is returned, the current task
     /// will be notified once the outstanding borrow is released.
     pub fn poll_ready(&mut self) -> Poll<(), BorrowError> {
-        self.inner.state.task.register();
-
-        match self.inner.state.borrowed.load(Acquire) {
-            UNUSED => Ok(Async::Ready(())),
-            BORROWED => Ok(Async::NotReady),
-            POISONED => Err(BorrowError::new()),
-            _ => unreachable!(),
-        }
+        self.inner.state.poll_ready(self.key)
     }
 
     /// Attempt to borrow the value, returning `NotReady` if it cannot be
     /// borrowed.
+    ///
+    /// Returns `Err` if the value is poisoned.
+    #[track_caller]
     pub fn poll_borrow(&mut self) -> Poll<BorrowGuard<T>, BorrowError> {
-        self.inner.state.task.register();
+        try_ready!(self.inner.state.poll_acquire(self.key, Access::Exclusive, false));
 
-        match self.inner.state.borrowed.compare_and_swap(UNUSED, BORROWED, Acquire) {
-            UNUSED => {
-                // Lock acquired, fall through
-            }
-            BORROWED => return Ok(Async::NotReady),
-            POISONED => return Err(BorrowError::new()),
-            _ => unreachable!(),
-        }
+        Ok(Async::Ready(BorrowGuard::new(&self.inner, Site::caller())))
+    }
 
-        let value_ptr = self.inner.value.get();
-        let handle = BorrowHandle {
-            state_ptr: &self.inner.state as *const State,
-            _inner: self.inner.clone() as Arc<Any>,
-        };
+    /// Attempt to borrow the value, returning `Err` if it cannot be borrowed.
+    #[track_caller]
+    pub fn try_borrow(&self) -> Result<BorrowGuard<T>, TryBorrowError> {
+        self.inner.state.try_acquire(Some(self.key), Access::Exclusive, false)?;
 
-        Ok(Async::Ready(BorrowGuard {
-            value_ptr,
-            handle,
-        }))
+        Ok(BorrowGuard::new(&self.inner, Site::caller()))
+    }
+
+    /// Attempt to borrow the value, returning `NotReady` if it cannot be
+    /// borrowed.
+    ///
+    /// Unlike `poll_borrow`, the borrow is acquired even if the value is
+    /// poisoned. The value may be in an inconsistent state, use `is_poisoned`
+    /// to check.
+    #[track_caller]
+    pub fn poll_borrow_ignore_poison(&mut self) -> Async<BorrowGuard<T>> {
+        match self.inner.state.poll_acquire(self.key, Access::Exclusive, true) {
+            Ok(Async::Ready(())) => Async::Ready(BorrowGuard::new(&self.inner, Site::caller())),
+            Ok(Async::NotReady) => Async::NotReady,
+            Err(_) => unreachable!(),
+        }
     }
 
     /// Attempt to borrow the value, returning `Err` if it cannot be borrowed.
-    pub fn try_borrow(&self) -> Result<BorrowGuard<T>, TryBorrowError> {
-        match self.inner.state.borrowed.compare_and_swap(UNUSED, BORROWED, Acquire) {
-            UNUSED => {
-                // Lock acquired, fall through
-            }
-            BORROWED => return Err(TryBorrowError::new(false)),
-            POISONED => return Err(TryBorrowError::new(true)),
-            _ => unreachable!(),
+    ///
+    /// Unlike `try_borrow`, the borrow is acquired even if the value is
+    /// poisoned. The value may be in an inconsistent state, use `is_poisoned`
+    /// to check.
+    #[track_caller]
+    pub fn try_borrow_ignore_poison(&self) -> Result<BorrowGuard<T>, TryBorrowError> {
+        self.inner.state.try_acquire(Some(self.key), Access::Exclusive, true)?;
+
+        Ok(BorrowGuard::new(&self.inner, Site::caller()))
+    }
+
+    /// Returns `true` if the value is poisoned.
+    ///
+    /// The value becomes poisoned when an exclusive borrow is released while
+    /// the thread is panicking.
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.state.is_poisoned()
+    }
+
+    /// Clear the poisoned state, allowing the value to be borrowed again.
+    ///
+    /// This should only be called once the value has been restored to a
+    /// consistent state.
+    pub fn clear_poison(&self) {
+        self.inner.state.borrowed.fetch_and(!POISONED, Release);
+    }
+
+    /// Returns the modification version of the value.
+    ///
+    /// The version is incremented each time an exclusive borrow through which
+    /// the value was mutably accessed is released. Borrows that only read the
+    /// value leave the version unchanged.
+    pub fn version(&self) -> usize {
+        self.inner.state.version.load(Acquire)
+    }
+
+    /// Returns the current version once it differs from `since`, returning
+    /// `NotReady` otherwise.
+    ///
+    /// When `NotReady` is returned, the current task will be notified once an
+    /// exclusive borrow that mutated the value is released. The value is not
+    /// borrowed, so observers do not contend with tasks borrowing the value.
+    pub fn poll_changed(&mut self, since: usize) -> Async<usize> {
+        self.inner.state.poll_changed(self.key, since)
+    }
+
+    /// Attempt to acquire a shared borrow of the value, returning `NotReady`
+    /// if the value is currently exclusively borrowed.
+    ///
+    /// When `NotReady` is returned, the current task will be notified once the
+    /// exclusive borrow is released.
+    #[track_caller]
+    pub fn poll_borrow_shared(&mut self) -> Poll<SharedGuard<T>, BorrowError> {
+        try_ready!(self.inner.state.poll_acquire(self.key, Access::Shared, false));
+
+        Ok(Async::Ready(SharedGuard::new(&self.inner, Site::caller())))
+    }
+
+    /// Attempt to acquire a shared borrow of the value, returning `Err` if the
+    /// value is currently exclusively borrowed.
+    #[track_caller]
+    pub fn try_borrow_shared(&self) -> Result<SharedGuard<T>, TryBorrowError> {
+        self.inner.state.try_acquire(Some(self.key), Access::Shared, false)?;
+
+        Ok(SharedGuard::new(&self.inner, Site::caller()))
+    }
+
+    /// Attempt to acquire an upgradeable borrow of the value, returning
+    /// `NotReady` if the value is currently exclusively borrowed or another
+    /// upgradeable borrow is outstanding.
+    ///
+    /// When `NotReady` is returned, the current task will be notified once the
+    /// outstanding borrow is released.
+    #[track_caller]
+    pub fn poll_borrow_upgradeable(&mut self) -> Poll<UpgradeableGuard<T>, BorrowError> {
+        try_ready!(self.inner.state.poll_acquire(self.key, Access::Upgradeable, false));
+
+        Ok(Async::Ready(UpgradeableGuard::new(&self.inner, Site::caller())))
+    }
+
+    /// Attempt to acquire an upgradeable borrow of the value, returning `Err`
+    /// if the value is currently exclusively borrowed or another upgradeable
+    /// borrow is outstanding.
+    #[track_caller]
+    pub fn try_borrow_upgradeable(&self) -> Result<UpgradeableGuard<T>, TryBorrowError> {
+        self.inner.state.try_acquire(Some(self.key), Access::Upgradeable, false)?;
+
+        Ok(UpgradeableGuard::new(&self.inner, Site::caller()))
+    }
+
+    /// Create a new `WeakBorrow` handle to the value.
+    pub fn downgrade(&self) -> WeakBorrow<T> {
+        WeakBorrow {
+            inner: Arc::downgrade(&self.inner),
         }
+    }
 
-        let value_ptr = self.inner.value.get();
-        let handle = BorrowHandle {
-            state_ptr: &self.inner.state as *const State,
-            _inner: self.inner.clone() as Arc<Any>,
-        };
+    /// Returns a mutable reference to the value.
+    ///
+    /// This does not go through the runtime borrow checks. Instead, `None` is
+    /// returned if other `Borrow` or `WeakBorrow` handles to the value exist or
+    /// if the value is currently borrowed.
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        Arc::get_mut(&mut self.inner)
+            .map(|inner| unsafe { &mut *inner.value.get() })
+    }
+
+    /// Returns information about the outstanding borrows of the value.
+    ///
+    /// Only available with the `diagnostics` feature.
+    #[cfg(feature = "diagnostics")]
+    pub fn holders(&self) -> Vec<Holder> {
+        self.inner.state.holders.snapshot()
+    }
+
+    /// Returns information about the borrows of the value that have been held
+    /// for longer than `threshold`.
+    ///
+    /// This can be called periodically by a watchdog task in order to detect
+    /// stuck borrows. Only available with the `diagnostics` feature.
+    #[cfg(feature = "diagnostics")]
+    pub fn long_held(&self, threshold: Duration) -> Vec<Holder> {
+        self.inner.state.holders.snapshot()
+            .into_iter()
+            .filter(|holder| holder.held_for() > threshold)
+            .collect()
+    }
+
+    /// Returns a snapshot of the contention metrics of the value.
+    ///
+    /// The metrics are shared by all handles to the value. Only available with
+    /// the `stats` feature.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> BorrowStats {
+        self.inner.state.stats.snapshot()
+    }
+
+    /// Convert the `Borrow` to a `Borrow` of an unsized type, such as a slice
+    /// or a trait object.
+    ///
+    /// `f` must perform the unsizing coercion, which is typically done by
+    /// passing the identity closure and naming the target type. All handles to
+    /// the value share the same borrow state, regardless of the type they
+    /// refer to it as.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use futures_borrow::Borrow;
+    /// use std::fmt::Debug;
+    ///
+    /// let borrow = Borrow::new([1, 2, 3]).unsize::<[u8], _>(|b| b);
+    /// assert_eq!(borrow.try_borrow().unwrap().len(), 3);
+    ///
+    /// let borrow = Borrow::new("hello").unsize::<dyn Debug, _>(|b| b);
+    /// assert_eq!(format!("{:?}", &*borrow.try_borrow().unwrap()), "\"hello\"");
+    /// ```
+    pub fn unsize<U: ?Sized, F>(self, f: F) -> Borrow<U>
+    where F: FnOnce(Arc<Inner<T>>) -> Arc<Inner<U>>,
+    {
+        let inner = f(self.into_arc());
+        Borrow::from_inner(inner)
+    }
+
+    /// Returns a future that resolves to a `BorrowGuard` once the value can
+    /// be borrowed.
+    ///
+    /// The future holds its own handle to the value, so it is not tied to the
+    /// lifetime of `self` and can be moved into other futures. Dropping the
+    /// future before it completes gives up its place in the wait.
+    #[track_caller]
+    pub fn borrow(&self) -> BorrowFuture<T> {
+        self.inner.state.handles.fetch_add(1, Relaxed);
+
+        BorrowFuture {
+            inner: self.inner.clone(),
+            key: self.inner.state.insert_waiter(),
+            site: Site::caller(),
+        }
+    }
+
+    /// Attempt to borrow the value once `pred` holds, returning `NotReady` if
+    /// the value cannot be borrowed or `pred` returns `false`.
+    ///
+    /// When `pred` returns `false`, the borrow is released and the current task
+    /// is notified once a guard that mutated the value is dropped, at which
+    /// point `pred` should be checked again. This is similar to waiting on a
+    /// condition variable.
+    ///
+    /// Returns `Err` if the value is poisoned.
+    #[track_caller]
+    pub fn poll_borrow_when<F>(&mut self, mut pred: F) -> Poll<BorrowGuard<T>, BorrowError>
+    where F: FnMut(&T) -> bool,
+    {
+        poll_borrow_when(&self.inner, self.key, Site::caller(), &mut pred)
+    }
+
+    /// Returns a future that resolves to a `BorrowGuard` once the value can be
+    /// borrowed and `pred` holds.
+    ///
+    /// See `poll_borrow_when` for details.
+    #[track_caller]
+    pub fn borrow_when<F>(&self, pred: F) -> BorrowWhen<T, F>
+    where F: FnMut(&T) -> bool,
+    {
+        BorrowWhen {
+            future: self.borrow(),
+            pred,
+        }
+    }
 
-        Ok(BorrowGuard {
-            value_ptr,
-            handle,
-        })
+    /// Borrow the value and run the future returned by `f` with the guard.
+    ///
+    /// Once the value can be borrowed, `f` is called with the guard and the
+    /// returned future is driven to completion. The guard is moved into the
+    /// future, so the borrow is held for as long as the future is alive and
+    /// released once it completes or is dropped, unless the future hands the
+    /// guard back as its output. Together with the `Future`, `Stream` and
+    /// `Sink` implementations of `BorrowGuard`, this allows driving a borrowed
+    /// sink or stream with the futures combinators.
+    ///
+    /// Errors acquiring the borrow are converted into the future's error type.
+    #[track_caller]
+    pub fn with_borrow<F, U>(&self, f: F) -> WithBorrow<T, F, U::Future>
+    where F: FnOnce(BorrowGuard<T>) -> U,
+          U: IntoFuture,
+          U::Error: From<BorrowError>,
+    {
+        WithBorrow {
+            future: self.borrow(),
+            f: Some(f),
+            running: None,
+        }
     }
+}
 
+/// Projections of guards into components of the borrowed data.
+///
+/// The projected guard keeps the value alive without referring to its type,
+/// so these require the borrowed type to be `'static`. For the same reason,
+/// the projected guard may drop the value on any thread it is sent to, so the
+/// borrowed type must also be `Send`.
+impl<T: ?Sized + 'static> Borrow<T> {
     /// Make a new `BorrowGuard` for a component of the borrowed data.
     ///
     /// The `BorrowGuard` is already mutably borrowed, so this cannot fail.
-    pub fn map<F, U>(mut r: BorrowGuard<T>, f: F) -> BorrowGuard<U>
+    pub fn map<F, U: ?Sized>(r: BorrowGuard<T>, f: F) -> BorrowGuard<U>
     where F: FnOnce(&mut T) -> &mut U,
+          T: Send,
     {
-        let u = f(&mut *r) as *mut U;
+        // Projecting does not count as a mutation, the projected guard tracks
+        // its own mutable accesses.
+        let u = f(unsafe { &mut *r.value_ptr }) as *mut U;
 
         BorrowGuard {
             value_ptr: u,
             handle: r.handle,
+            _p: PhantomData,
         }
     }
 
     /// Make a new `BorrowGuard` for a component of the borrowed data.
     ///
     /// The `BorrowGuard` is already mutably borrowed, so this cannot fail.
-    pub fn try_map<F, U, E>(mut r: BorrowGuard<T>, f: F)
+    pub fn try_map<F, U: ?Sized, E>(r: BorrowGuard<T>, f: F)
         -> Result<BorrowGuard<U>, (BorrowGuard<T>, E)>
-    where F: FnOnce(&mut T) -> Result<&mut U, E>
+    where F: FnOnce(&mut T) -> Result<&mut U, E>,
+          T: Send,
     {
 
-        let res = f(&mut *r)
+        let res = f(unsafe { &mut *r.value_ptr })
             .map(|u| u as *mut U);
 
         match res {
@@ -210,6 +826,7 @@ impl<T: 'static> Borrow<T> {
                 Ok(BorrowGuard {
                     value_ptr: u,
                     handle: r.handle,
+                    _p: PhantomData,
                 })
             }
             Err(e) => {
@@ -217,93 +834,1095 @@ impl<T: 'static> Borrow<T> {
             }
         }
     }
+
+    /// Split a `BorrowGuard` into two guards for disjoint components of the
+    /// borrowed data.
+    ///
+    /// Both guards hold the same exclusive borrow, which is only released
+    /// once both of them have been dropped. Either of them may be the last
+    /// one, so a value that cannot be sent to another thread is rejected:
+    ///
+    /// ```compile_fail,E0277
+    /// # use futures_borrow::Borrow;
+    /// use std::rc::Rc;
+    ///
+    /// fn assert_send<T: Send>(_: T) {}
+    ///
+    /// let borrow = Borrow::new((Rc::new(()), 1u32, 2u32));
+    /// let guard = borrow.try_borrow().unwrap();
+    ///
+    /// let (a, b) = Borrow::map_split(guard, |v| (&mut v.1, &mut v.2));
+    /// assert_send(a);
+    /// assert_send(b);
+    /// ```
+    pub fn map_split<F, U: ?Sized, V: ?Sized>(r: BorrowGuard<T>, f: F)
+        -> (BorrowGuard<U>, BorrowGuard<V>)
+    where F: FnOnce(&mut T) -> (&mut U, &mut V),
+          T: Send,
+    {
+        let (u, v) = f(unsafe { &mut *r.value_ptr });
+        let (u, v) = (u as *mut U, v as *mut V);
+
+        let handle = r.handle.split();
+
+        (
+            BorrowGuard {
+                value_ptr: u,
+                handle: r.handle,
+                _p: PhantomData,
+            },
+            BorrowGuard {
+                value_ptr: v,
+                handle,
+                _p: PhantomData,
+            },
+        )
+    }
+
+    /// Make a new `SharedGuard` for a component of the borrowed data.
+    ///
+    /// The projected guard is `Send` based on `U` alone, but it keeps the `T`
+    /// alive and may drop it on the thread it was sent to. Projecting a value
+    /// that cannot be shared across threads is rejected:
+    ///
+    /// ```compile_fail,E0277
+    /// # use futures_borrow::Borrow;
+    /// use std::rc::Rc;
+    ///
+    /// fn assert_send<T: Send>(_: T) {}
+    ///
+    /// let borrow = Borrow::new((Rc::new(()), 1u32));
+    /// let guard = borrow.try_borrow_shared().unwrap();
+    ///
+    /// assert_send(Borrow::map_shared(guard, |v| &v.1));
+    /// ```
+    pub fn map_shared<F, U: ?Sized>(r: SharedGuard<T>, f: F) -> SharedGuard<U>
+    where F: FnOnce(&T) -> &U,
+          T: Send + Sync,
+    {
+        let u = f(&*r) as *const U;
+
+        SharedGuard {
+            value_ptr: u,
+            handle: r.handle,
+            _p: PhantomData,
+        }
+    }
 }
 
-impl<T: Default + 'static> Default for Borrow<T> {
+impl<T: Default> Default for Borrow<T> {
     fn default() -> Borrow<T> {
         Borrow::new(T::default())
     }
 }
 
-impl<T: fmt::Debug + 'static> fmt::Debug for Borrow<T> {
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Borrow<T> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        match self.try_borrow() {
-            Ok(guard) => {
+        // Formatting neither waits for its turn nor counts as a borrow in the
+        // metrics.
+        match self.inner.state.acquire(Access::Shared, true) {
+            Ok(()) => {
+                let mut guard = SharedGuard::new(&self.inner, Site::caller());
+                guard.handle.acquired = Timestamp::default();
+
                 fmt.debug_struct("Borrow")
-                    .field("data", &*guard)
+                    .field("data", &&*guard)
+                    .field("poisoned", &self.is_poisoned())
+                    .finish()
+            }
+            Err(_) => {
+                let mut fmt = fmt.debug_struct("Borrow");
+
+                #[cfg(feature = "diagnostics")]
+                fmt.field("holders", &self.holders());
+
+                #[cfg(not(feature = "diagnostics"))]
+                fmt.field("data", &"<<borrowed>>");
+
+                fmt.field("poisoned", &self.is_poisoned())
                     .finish()
             }
-            Err(e) => {
-                if e.is_poisoned() {
-                    fmt.debug_struct("Borrow")
-                        .field("data", &"Poisoned")
-                        .finish()
-                } else {
-                    fmt.debug_struct("Borrow")
-                        .field("data", &"<<borrowed>>")
-                        .finish()
-                }
-            },
         }
     }
 }
 
-unsafe impl<T: Send> Send for Borrow<T> { }
-unsafe impl<T: Send> Sync for Borrow<T> { }
+impl<T: ?Sized> Clone for Borrow<T> {
+    fn clone(&self) -> Borrow<T> {
+        Borrow::from_inner(self.inner.clone())
+    }
+}
 
-// ===== impl BorrowGuard =====
+impl<T: ?Sized> Borrow<T> {
+    fn from_inner(inner: Arc<Inner<T>>) -> Borrow<T> {
+        inner.state.handles.fetch_add(1, Relaxed);
 
-impl<T> ops::Deref for BorrowGuard<T> {
-    type Target = T;
+        let key = inner.state.insert_waiter();
+        Borrow { inner, key }
+    }
 
-    fn deref(&self) -> &T {
-        unsafe { &*self.value_ptr }
+    /// Consumes the handle, returning the inner `Arc`.
+    fn into_arc(self) -> Arc<Inner<T>> {
+        self.inner.state.remove_waiter(self.key);
+        self.inner.state.handles.fetch_sub(1, Release);
+
+        let inner = unsafe { ptr::read(&self.inner) };
+        mem::forget(self);
+        inner
     }
 }
 
-impl<T> ops::DerefMut for BorrowGuard<T> {
-    fn deref_mut(&mut self) -> &mut T {
-        unsafe { &mut *self.value_ptr }
+impl<T: ?Sized> Drop for Borrow<T> {
+    fn drop(&mut self) {
+        self.inner.state.remove_waiter(self.key);
+        self.inner.state.handles.fetch_sub(1, Release);
     }
 }
 
-impl<T: fmt::Debug> fmt::Debug for BorrowGuard<T> {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.debug_struct("BorrowGuard")
-            .field("data", &**self)
+unsafe impl<T: ?Sized + Send + Sync> Send for Borrow<T> { }
+unsafe impl<T: ?Sized + Send + Sync> Sync for Borrow<T> { }
+
+// ===== impl WeakBorrow =====
+
+impl<T: ?Sized> WeakBorrow<T> {
+    /// Attempt to upgrade the `WeakBorrow` to a `Borrow`.
+    ///
+    /// Returns `None` if the value has since been dropped.
+    pub fn upgrade(&self) -> Option<Borrow<T>> {
+        self.inner.upgrade().map(Borrow::from_inner)
+    }
+}
+
+impl<T: ?Sized> Clone for WeakBorrow<T> {
+    fn clone(&self) -> WeakBorrow<T> {
+        WeakBorrow {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for WeakBorrow<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("WeakBorrow")
+            .finish()
+    }
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Send for WeakBorrow<T> { }
+unsafe impl<T: ?Sized + Send + Sync> Sync for WeakBorrow<T> { }
+
+// ===== impl BorrowGuard =====
+
+impl<T: ?Sized> BorrowGuard<T> {
+    fn new(inner: &Arc<Inner<T>>, site: Site) -> BorrowGuard<T> {
+        BorrowGuard {
+            value_ptr: inner.value.get(),
+            handle: BorrowHandle::new(inner, Access::Exclusive, site),
+            _p: PhantomData,
+        }
+    }
+
+    /// Atomically downgrade the exclusive borrow to a shared borrow.
+    ///
+    /// No other exclusive borrow can be acquired between the release of the
+    /// exclusive borrow and the acquisition of the shared one. Tasks waiting
+    /// for a shared borrow are notified.
+    ///
+    /// If the guard has been split with `Borrow::map_split`, the value remains
+    /// exclusively borrowed until the other halves are released.
+    pub fn downgrade(r: BorrowGuard<T>) -> SharedGuard<T> {
+        SharedGuard {
+            value_ptr: r.value_ptr,
+            handle: r.handle.downgrade(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> ops::Deref for BorrowGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.value_ptr }
+    }
+}
+
+impl<T: ?Sized> ops::DerefMut for BorrowGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.handle.mutated = true;
+        unsafe { &mut *self.value_ptr }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for BorrowGuard<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("BorrowGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<T: ?Sized + Future> Future for BorrowGuard<T> {
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn poll(&mut self) -> Poll<T::Item, T::Error> {
+        (**self).poll()
+    }
+}
+
+impl<T: ?Sized + Stream> Stream for BorrowGuard<T> {
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn poll(&mut self) -> Poll<Option<T::Item>, T::Error> {
+        (**self).poll()
+    }
+}
+
+impl<T: ?Sized + Sink> Sink for BorrowGuard<T> {
+    type SinkItem = T::SinkItem;
+    type SinkError = T::SinkError;
+
+    fn start_send(&mut self, item: T::SinkItem) -> StartSend<T::SinkItem, T::SinkError> {
+        (**self).start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), T::SinkError> {
+        (**self).poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), T::SinkError> {
+        (**self).close()
+    }
+}
+
+unsafe impl<T: ?Sized + Send> Send for BorrowGuard<T> { }
+unsafe impl<T: ?Sized + Sync> Sync for BorrowGuard<T> { }
+
+// ===== impl SharedGuard =====
+
+impl<T: ?Sized> SharedGuard<T> {
+    fn new(inner: &Arc<Inner<T>>, site: Site) -> SharedGuard<T> {
+        SharedGuard {
+            value_ptr: inner.value.get(),
+            handle: BorrowHandle::new(inner, Access::Shared, site),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> ops::Deref for SharedGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.value_ptr }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SharedGuard<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SharedGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+// The guard may hold the last reference to the value, dropping it on the
+// thread it was sent to.
+unsafe impl<T: ?Sized + Send + Sync> Send for SharedGuard<T> { }
+unsafe impl<T: ?Sized + Sync> Sync for SharedGuard<T> { }
+
+// ===== impl UpgradeableGuard =====
+
+impl<T: ?Sized> UpgradeableGuard<T> {
+    fn new(inner: &Arc<Inner<T>>, site: Site) -> UpgradeableGuard<T> {
+        UpgradeableGuard {
+            value_ptr: inner.value.get(),
+            handle: BorrowHandle::new(inner, Access::Upgradeable, site),
+            _p: PhantomData,
+        }
+    }
+
+    /// Returns a future that promotes the borrow to an exclusive borrow once
+    /// all shared borrows have been released.
+    ///
+    /// The upgradeable borrow is held while waiting, so no other exclusive
+    /// borrow can be acquired in the meantime. Dropping the future releases
+    /// the borrow.
+    pub fn upgrade(r: UpgradeableGuard<T>) -> Upgrade<T> {
+        let key = r.handle.state().insert_waiter();
+
+        Upgrade {
+            guard: Some(r),
+            key,
+            _p: PhantomData,
+        }
+    }
+
+    /// Atomically downgrade the upgradeable borrow to a shared borrow.
+    ///
+    /// Tasks waiting for an exclusive or upgradeable borrow are notified.
+    pub fn downgrade(r: UpgradeableGuard<T>) -> SharedGuard<T> {
+        SharedGuard {
+            value_ptr: r.value_ptr,
+            handle: r.handle.downgrade(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> ops::Deref for UpgradeableGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.value_ptr }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for UpgradeableGuard<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("UpgradeableGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Send for UpgradeableGuard<T> { }
+unsafe impl<T: ?Sized + Sync> Sync for UpgradeableGuard<T> { }
+
+// ===== impl Upgrade =====
+
+impl<T: ?Sized> Future for Upgrade<T> {
+    type Item = BorrowGuard<T>;
+
+    /// The upgrade cannot fail, the error type matches `BorrowFuture`.
+    type Error = BorrowError;
+
+    fn poll(&mut self) -> Poll<BorrowGuard<T>, BorrowError> {
+        {
+            let guard = self.guard.as_ref().expect("polled after completion");
+            let state = guard.handle.state();
+
+            if !state.poll_upgrade(self.key).is_ready() {
+                return Ok(Async::NotReady);
+            }
+
+            state.remove_waiter(self.key);
+        }
+
+        let mut guard = self.guard.take().unwrap();
+        guard.handle.set_access(Access::Exclusive);
+
+        Ok(Async::Ready(BorrowGuard {
+            value_ptr: guard.value_ptr,
+            handle: guard.handle,
+            _p: PhantomData,
+        }))
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for Upgrade<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Upgrade")
+            .finish()
+    }
+}
+
+impl<T: ?Sized> Drop for Upgrade<T> {
+    fn drop(&mut self) {
+        if let Some(ref guard) = self.guard {
+            guard.handle.state().remove_waiter(self.key);
+        }
+    }
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Send for Upgrade<T> { }
+unsafe impl<T: ?Sized + Sync> Sync for Upgrade<T> { }
+
+// ===== impl BorrowFuture =====
+
+impl<T: ?Sized> BorrowFuture<T> {
+    /// Stop waiting once `delay` completes, failing with an error for which
+    /// `is_timeout` returns `true`.
+    ///
+    /// Any future can be used as the time source, for example a timer
+    /// provided by the runtime or a mock clock in tests. When the borrow and
+    /// the delay are ready at the same time, the borrow wins.
+    pub fn timeout<D: Future>(self, delay: D) -> BorrowUntil<T, D> {
+        BorrowUntil {
+            future: self,
+            signal: delay,
+            kind: Kind::TimedOut,
+        }
+    }
+
+    /// Stop waiting once `signal` completes, failing with an error for which
+    /// `is_canceled` returns `true`.
+    pub fn cancel_on<C: Future>(self, signal: C) -> BorrowUntil<T, C> {
+        BorrowUntil {
+            future: self,
+            signal,
+            kind: Kind::Canceled,
+        }
+    }
+}
+
+impl<T: ?Sized> Future for BorrowFuture<T> {
+    type Item = BorrowGuard<T>;
+    type Error = BorrowError;
+
+    fn poll(&mut self) -> Poll<BorrowGuard<T>, BorrowError> {
+        try_ready!(self.inner.state.poll_acquire(self.key, Access::Exclusive, false));
+        Ok(Async::Ready(BorrowGuard::new(&self.inner, self.site)))
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for BorrowFuture<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("BorrowFuture")
             .finish()
     }
 }
 
-unsafe impl<T: Send> Send for BorrowGuard<T> { }
-unsafe impl<T: Sync> Sync for BorrowGuard<T> { }
+impl<T: ?Sized> Drop for BorrowFuture<T> {
+    fn drop(&mut self) {
+        self.inner.state.remove_waiter(self.key);
+        self.inner.state.handles.fetch_sub(1, Release);
+    }
+}
+
+unsafe impl<T: ?Sized + Send> Send for BorrowFuture<T> { }
+unsafe impl<T: ?Sized + Send> Sync for BorrowFuture<T> { }
+
+// ===== impl BorrowWhen =====
+
+impl<T: ?Sized, F> Future for BorrowWhen<T, F>
+where F: FnMut(&T) -> bool,
+{
+    type Item = BorrowGuard<T>;
+    type Error = BorrowError;
+
+    fn poll(&mut self) -> Poll<BorrowGuard<T>, BorrowError> {
+        let future = &self.future;
+        poll_borrow_when(&future.inner, future.key, future.site, &mut self.pred)
+    }
+}
+
+impl<T: ?Sized, F> fmt::Debug for BorrowWhen<T, F> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("BorrowWhen")
+            .finish()
+    }
+}
+
+// ===== impl WithBorrow =====
+
+impl<T: ?Sized, F, U> Future for WithBorrow<T, F, U::Future>
+where F: FnOnce(BorrowGuard<T>) -> U,
+      U: IntoFuture,
+      U::Error: From<BorrowError>,
+{
+    type Item = U::Item;
+    type Error = U::Error;
+
+    fn poll(&mut self) -> Poll<U::Item, U::Error> {
+        if self.running.is_none() {
+            let guard = try_ready!(self.future.poll());
+            let f = self.f.take().expect("polled after completion");
+            self.running = Some(f(guard).into_future());
+        }
+
+        self.running.as_mut().unwrap().poll()
+    }
+}
+
+impl<T: ?Sized, F, U> fmt::Debug for WithBorrow<T, F, U> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("WithBorrow")
+            .field("borrowed", &self.running.is_some())
+            .finish()
+    }
+}
+
+// ===== impl BorrowUntil =====
+
+impl<T: ?Sized, F: Future> Future for BorrowUntil<T, F> {
+    type Item = BorrowGuard<T>;
+    type Error = BorrowError;
+
+    fn poll(&mut self) -> Poll<BorrowGuard<T>, BorrowError> {
+        if let Async::Ready(guard) = self.future.poll()? {
+            return Ok(Async::Ready(guard));
+        }
+
+        match self.signal.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // A failed signal, such as a timer that shut down, also stops the
+            // wait: the borrow could otherwise wait forever.
+            _ => {
+                // Give up the place in the queue without waiting for the
+                // future to be dropped.
+                self.future.inner.state.stop_waiting(self.future.key);
+
+                Err(BorrowError::new(self.kind))
+            }
+        }
+    }
+}
+
+impl<T: ?Sized, F> fmt::Debug for BorrowUntil<T, F> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("BorrowUntil")
+            .field("kind", &self.kind)
+            .finish()
+    }
+}
+
+// ===== impl Unwrap =====
+
+impl<T> Future for Unwrap<T> {
+    type Item = T;
+    type Error = Borrow<T>;
+
+    fn poll(&mut self) -> Poll<T, Borrow<T>> {
+        let borrow = self.borrow.take().expect("polled after completion");
+
+        let borrow = match borrow.try_unwrap() {
+            Ok(value) => return Ok(Async::Ready(value)),
+            Err(borrow) => borrow,
+        };
+
+        if borrow.inner.state.poll_released(borrow.key).is_ready() {
+            if borrow.inner.state.handles.load(Acquire) > 1 {
+                // Nothing is borrowed, so the value is held by other handles.
+                return Err(borrow);
+            }
+
+            // The remaining references belong to guards that released the
+            // borrow but are still being dropped, check again once they are.
+            task::current().notify();
+        }
+
+        self.borrow = Some(borrow);
+        Ok(Async::NotReady)
+    }
+}
+
+impl<T> fmt::Debug for Unwrap<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Unwrap")
+            .finish()
+    }
+}
 
 // ===== impl BorrowHandle =====
 
+impl BorrowHandle {
+    fn new<T: ?Sized>(inner: &Arc<Inner<T>>, access: Access, site: Site) -> BorrowHandle {
+        // The reference is released by `drop_ref` when the handle is dropped.
+        mem::forget(inner.clone());
+
+        BorrowHandle {
+            state_ptr: Arc::as_ptr(inner) as *const State,
+            access,
+            holder: inner.state.holders.insert(site, access),
+            acquired: Timestamp::now(),
+            mutated: false,
+        }
+    }
+
+    fn state(&self) -> &State {
+        unsafe { &*self.state_ptr }
+    }
+
+    /// Create an additional handle to the same exclusive borrow.
+    fn split(&self) -> BorrowHandle {
+        debug_assert_eq!(self.access, Access::Exclusive);
+
+        let state = self.state();
+        state.split.fetch_add(1, Relaxed);
+
+        unsafe { (state.clone_ref)(self.state_ptr) };
+
+        BorrowHandle {
+            state_ptr: self.state_ptr,
+            access: Access::Exclusive,
+            holder: state.holders.duplicate(&self.holder),
+            // The hold time is accounted for by the original handle.
+            acquired: Timestamp::default(),
+            mutated: false,
+        }
+    }
+
+    fn set_access(&mut self, access: Access) {
+        self.state().holders.set_access(&self.holder, access);
+        self.access = access;
+    }
+
+    /// Convert the handle to a shared borrow.
+    ///
+    /// The shared borrow is acquired before the current one is released, so
+    /// no exclusive borrow can be acquired in between.
+    fn downgrade(mut self) -> BorrowHandle {
+        debug_assert!(self.access != Access::Shared);
+
+        if mem::replace(&mut self.mutated, false) {
+            self.state().changed();
+        }
+
+        let state = self.state();
+        let prev = state.borrowed.fetch_add(SHARED, Acquire);
+
+        if prev & SHARED_MASK == SHARED_MASK {
+            panic!("too many shared borrows");
+        }
+
+        state.release(self.access);
+
+        self.set_access(Access::Shared);
+        self
+    }
+}
+
 impl Drop for BorrowHandle {
     fn drop(&mut self) {
         let state = unsafe { &*self.state_ptr };
+        state.holders.remove(&self.holder);
+        state.stats.released(&self.acquired);
+
+        if self.mutated {
+            state.changed();
+        }
+
+        state.release(self.access);
+
+        // The state may be freed here, so it must not be accessed after.
+        unsafe { (state.drop_ref)(self.state_ptr) };
+    }
+}
+
+/// Attempt to borrow the value of `inner` once `pred` holds, on behalf of the
+/// waiter identified by `key`.
+fn poll_borrow_when<T: ?Sized, F>(inner: &Arc<Inner<T>>, key: usize, site: Site, pred: &mut F)
+    -> Poll<BorrowGuard<T>, BorrowError>
+where F: FnMut(&T) -> bool,
+{
+    loop {
+        try_ready!(inner.state.poll_acquire(key, Access::Exclusive, false));
+
+        let guard = BorrowGuard::new(inner, site);
+
+        if pred(&guard) {
+            return Ok(Async::Ready(guard));
+        }
+
+        // The version cannot change while the borrow is held.
+        let version = inner.state.version.load(Acquire);
+        drop(guard);
+
+        if !inner.state.poll_changed(key, version).is_ready() {
+            return Ok(Async::NotReady);
+        }
+    }
+}
+
+/// Increments the reference count of the `Arc<Inner<T>>` that `state` points
+/// to. `Inner` is `repr(C)` with the state as the first field, so a pointer to
+/// the state is also a pointer to the `Inner`.
+unsafe fn clone_ref<T>(state: *const State) {
+    Arc::increment_strong_count(state as *const Inner<T>);
+}
+
+/// Decrements the reference count of the `Arc<Inner<T>>` that `state` points
+/// to.
+unsafe fn drop_ref<T>(state: *const State) {
+    Arc::decrement_strong_count(state as *const Inner<T>);
+}
+
+// ===== impl Access =====
+
+impl Access {
+    /// Returns `true` if the borrow may coexist with shared borrows.
+    fn is_shared(&self) -> bool {
+        *self != Access::Exclusive
+    }
+}
+
+// ===== impl State =====
+
+impl State {
+    /// Create the state for an `Inner<T>`.
+    fn new<T>(waiters: Arc<Mutex<Waiters>>, policy: Policy) -> State {
+        State {
+            borrowed: AtomicUsize::new(UNUSED),
+            policy,
+            split: AtomicUsize::new(0),
+            waiters,
+            clone_ref: clone_ref::<T>,
+            drop_ref: drop_ref::<T>,
+            holders: Holders::new(),
+            stats: Stats::new(),
+            version: AtomicUsize::new(0),
+            handles: AtomicUsize::new(0),
+        }
+    }
+
+    /// Attempt to acquire the borrow without waiting.
+    ///
+    /// When the caller owns the waiter identified by `key`, the waiter stops
+    /// waiting once the borrow is acquired.
+    fn try_acquire(&self, key: Option<usize>, access: Access, ignore_poison: bool)
+        -> Result<(), TryBorrowError>
+    {
+        let res = if self.policy != Policy::ReaderPriority {
+            // Skipping ahead of waiting tasks is not permitted, but a waiting
+            // caller may acquire when its turn has come.
+            let mut waiters = self.waiters();
+
+            if waiters.is_next(key, access.is_shared()) {
+                self.acquire(access, ignore_poison).map(|()| {
+                    if let Some(key) = key {
+                        waiters.dequeue(key);
+                    }
+                })
+            } else {
+                Err(TryBorrowError::new(!ignore_poison && self.is_poisoned()))
+            }
+        } else {
+            self.acquire(access, ignore_poison).map(|()| {
+                if let Some(key) = key {
+                    self.waiters().dequeue(key);
+                }
+            })
+        };
+
+        match res {
+            Ok(()) => self.stats.borrowed(),
+            Err(_) => self.stats.try_borrow_failed(),
+        }
+
+        res
+    }
+
+    /// Attempt to acquire the borrow on behalf of the waiter identified by
+    /// `key`, registering the current task for notification on failure.
+    fn poll_acquire(&self, key: usize, access: Access, ignore_poison: bool)
+        -> Poll<(), BorrowError>
+    {
+        if self.policy == Policy::ReaderPriority {
+            // Fast path, the waiter list is only locked once acquired
+            match self.acquire(access, ignore_poison) {
+                Ok(()) => {
+                    let since = self.waiters().dequeue(key);
+
+                    self.stats.borrowed();
+                    self.stats.waited(&since);
+
+                    return Ok(Async::Ready(()));
+                }
+                Err(ref e) if e.is_poisoned() => {
+                    return Err(BorrowError::new(Kind::Poisoned));
+                }
+                Err(_) => {}
+            }
+        }
+
+        let mut waiters = self.waiters();
+
+        // Try again while holding the lock. Releasing the borrow requires the
+        // lock in order to notify waiters, so the release cannot be missed.
+        let res = if waiters.is_next(Some(key), access.is_shared()) {
+            self.acquire(access, ignore_poison)
+        } else {
+            Err(TryBorrowError::new(!ignore_poison && self.is_poisoned()))
+        };
+
+        match res {
+            Ok(()) => {
+                let since = waiters.dequeue(key);
+
+                self.stats.borrowed();
+                self.stats.waited(&since);
+
+                Ok(Async::Ready(()))
+            }
+            Err(ref e) if e.is_poisoned() => {
+                // Let the following waiter observe the poisoning as well.
+                waiters.dequeue(key);
+                waiters.notify();
+
+                Err(BorrowError::new(Kind::Poisoned))
+            }
+            Err(_) => {
+                waiters.register(key, access.is_shared());
+                self.stats.not_ready();
+
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    /// Returns `Ready` when the waiter identified by `key` is able to acquire
+    /// an exclusive borrow.
+    fn poll_ready(&self, key: usize) -> Poll<(), BorrowError> {
+        let mut waiters = self.waiters();
+
+        let curr = self.borrowed.load(Acquire);
+
+        if curr & POISONED == POISONED {
+            // Let the following waiter observe the poisoning as well.
+            waiters.dequeue(key);
+            waiters.notify();
 
-        if thread::panicking() {
-            state.borrowed.store(POISONED, Release);
+            Err(BorrowError::new(Kind::Poisoned))
+        } else if curr & BORROWED_MASK == 0 && waiters.is_next(Some(key), false) {
+            Ok(Async::Ready(()))
         } else {
-            state.borrowed.store(UNUSED, Release);
+            waiters.register(key, false);
+            Ok(Async::NotReady)
         }
+    }
+
+    /// Returns `Ready` once no borrow is outstanding.
+    ///
+    /// The waiter identified by `key` does not take a place in the queue.
+    fn poll_released(&self, key: usize) -> Async<()> {
+        let mut waiters = self.waiters();
+
+        if self.borrowed.load(Acquire) & BORROWED_MASK == 0 {
+            return Async::Ready(());
+        }
+
+        waiters.watch(key);
+        Async::NotReady
+    }
+
+    /// Returns `Ready` once the upgradeable borrow held by the caller has been
+    /// promoted to an exclusive borrow.
+    ///
+    /// The waiter identified by `key` waits like an exclusive waiter, holding
+    /// back readers according to the policy.
+    fn poll_upgrade(&self, key: usize) -> Async<()> {
+        if self.upgrade() {
+            return Async::Ready(());
+        }
+
+        let mut waiters = self.waiters();
+
+        // Try again while holding the lock, see `poll_acquire`.
+        if self.upgrade() {
+            return Async::Ready(());
+        }
+
+        waiters.register_upgrade(key);
+        Async::NotReady
+    }
+
+    /// Update the borrow state, acquiring the borrow.
+    fn acquire(&self, access: Access, ignore_poison: bool) -> Result<(), TryBorrowError> {
+        let mut curr = self.borrowed.load(Relaxed);
+
+        loop {
+            if !ignore_poison && curr & POISONED == POISONED {
+                return Err(TryBorrowError::new(true));
+            }
+
+            let next = match access {
+                Access::Shared => {
+                    if curr & BORROWED == BORROWED {
+                        return Err(TryBorrowError::new(false));
+                    }
+
+                    if curr & SHARED_MASK == SHARED_MASK {
+                        panic!("too many shared borrows");
+                    }
+
+                    curr + SHARED
+                }
+                Access::Upgradeable => {
+                    if curr & (BORROWED | UPGRADEABLE) != 0 {
+                        return Err(TryBorrowError::new(false));
+                    }
+
+                    curr | UPGRADEABLE
+                }
+                Access::Exclusive => {
+                    if curr & BORROWED_MASK != 0 {
+                        return Err(TryBorrowError::new(false));
+                    }
+
+                    curr | BORROWED
+                }
+            };
+
+            let res = self.borrowed
+                .compare_exchange(curr, next, Acquire, Relaxed);
 
-        state.task.notify();
+            match res {
+                Ok(_) => return Ok(()),
+                Err(actual) => curr = actual,
+            }
+        }
+    }
+
+    /// Promote an upgradeable borrow to an exclusive borrow, returning `false`
+    /// if shared borrows are still outstanding.
+    fn upgrade(&self) -> bool {
+        let mut curr = self.borrowed.load(Relaxed);
+
+        loop {
+            debug_assert!(curr & UPGRADEABLE == UPGRADEABLE);
+
+            if curr & SHARED_MASK != 0 {
+                return false;
+            }
+
+            let next = (curr & !UPGRADEABLE) | BORROWED;
+
+            let res = self.borrowed
+                .compare_exchange(curr, next, Acquire, Relaxed);
+
+            match res {
+                Ok(_) => return true,
+                Err(actual) => curr = actual,
+            }
+        }
+    }
+
+    /// Release a borrow, notifying waiters.
+    fn release(&self, access: Access) {
+        if access == Access::Shared {
+            let prev = self.borrowed.fetch_sub(SHARED, Release);
+
+            // Only the last shared borrow notifies waiters.
+            if prev & SHARED_MASK != SHARED {
+                return;
+            }
+        } else if access == Access::Upgradeable {
+            self.borrowed.fetch_and(!UPGRADEABLE, Release);
+        } else {
+            if panicking::panicking() {
+                self.borrowed.fetch_or(POISONED, Relaxed);
+            }
+
+            // When the guard has been split, only the last handle releases
+            // the borrow.
+            let mut split = self.split.load(Relaxed);
+
+            while split > 0 {
+                let res = self.split
+                    .compare_exchange(split, split - 1, AcqRel, Relaxed);
+
+                match res {
+                    Ok(_) => return,
+                    Err(actual) => split = actual,
+                }
+            }
+
+            self.borrowed.fetch_and(!BORROWED, Release);
+        }
+
+        let mut waiters = self.waiters();
+        waiters.notify();
+        waiters.notify_changed(self.version.load(Acquire));
+    }
+
+    /// Record a mutation of the value.
+    ///
+    /// Waiters watching changes are notified once the borrow is released.
+    fn changed(&self) {
+        self.version.fetch_add(1, Release);
+    }
+
+    /// Returns the current version once it differs from `version`.
+    ///
+    /// The waiter identified by `key` does not take a place in the queue.
+    fn poll_changed(&self, key: usize, version: usize) -> Async<usize> {
+        let mut waiters = self.waiters();
+
+        // Checked while holding the lock, see `poll_acquire`.
+        let curr = self.version.load(Acquire);
+
+        if curr != version {
+            return Async::Ready(curr);
+        }
+
+        waiters.watch_changes(key, version);
+        Async::NotReady
+    }
+
+    /// Allocate a new entry in the waiter list, returning its key.
+    fn insert_waiter(&self) -> usize {
+        self.waiters().insert()
+    }
+
+    /// Stop waiting on behalf of the waiter identified by `key`, which keeps
+    /// its entry.
+    fn stop_waiting(&self, key: usize) {
+        let mut waiters = self.waiters();
+
+        waiters.dequeue(key);
+        waiters.notify();
+    }
+
+    /// Release the waiter entry identified by `key`.
+    fn remove_waiter(&self, key: usize) {
+        let mut waiters = self.waiters();
+
+        if waiters.remove(key) {
+            // The waiter was next in line, let the following one through.
+            waiters.notify();
+        }
+    }
+
+    fn is_poisoned(&self) -> bool {
+        self.borrowed.load(Acquire) & POISONED == POISONED
+    }
+
+    fn waiters(&self) -> MutexGuard<'_, Waiters> {
+        self.waiters.lock()
     }
 }
 
 // ===== impl BorrowError =====
 
 impl BorrowError {
-    fn new() -> BorrowError {
+    fn new(kind: Kind) -> BorrowError {
         BorrowError {
-            _priv: (),
+            kind,
+        }
+    }
+
+    /// Returns `true` if the borrow failed because the value is poisoned.
+    pub fn is_poisoned(&self) -> bool {
+        self.kind == Kind::Poisoned
+    }
+
+    /// Returns `true` if the borrow failed because its deadline expired.
+    pub fn is_timeout(&self) -> bool {
+        self.kind == Kind::TimedOut
+    }
+
+    /// Returns `true` if the borrow failed because it was canceled.
+    pub fn is_canceled(&self) -> bool {
+        self.kind == Kind::Canceled
+    }
+}
+
+impl fmt::Display for BorrowError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            Kind::Poisoned => write!(fmt, "borrowed value is poisoned"),
+            Kind::TimedOut => write!(fmt, "borrow timed out"),
+            Kind::Canceled => write!(fmt, "borrow canceled"),
         }
     }
 }
 
+#[cfg(feature = "std")]
+impl Error for BorrowError {
+}
+
 // ===== impl TryBorrowError =====
 
 impl TryBorrowError {
@@ -313,7 +1932,24 @@ impl TryBorrowError {
         }
     }
 
+    /// Returns `true` if the borrow failed because the value is poisoned.
+    ///
+    /// Otherwise, the borrow failed because the value is already borrowed.
     pub fn is_poisoned(&self) -> bool {
         self.is_poisoned
     }
 }
+
+impl fmt::Display for TryBorrowError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_poisoned {
+            write!(fmt, "borrowed value is poisoned")
+        } else {
+            write!(fmt, "value is already borrowed")
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for TryBorrowError {
+}
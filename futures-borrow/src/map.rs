@@ -0,0 +1,290 @@
+use {Access, BorrowError, BorrowGuard, BorrowHandle, Policy, State, TryBorrowError};
+use diagnostics::Site;
+use mutex::{Mutex, MutexGuard};
+use unsize::Inner;
+use waiters::Waiters;
+
+use futures::{Future, Poll, Async};
+
+use std::{fmt, ops, ptr};
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::sync::{Arc, Weak};
+
+/// A map of values that can be exclusively borrowed per key.
+///
+/// Entries are created on demand, initialized with `V::default()`, the first
+/// time a key is borrowed. Once no guard or pending `BorrowMapFuture` refers
+/// to an entry, it is evicted from the map and its value dropped. This makes
+/// `BorrowMap<K, ()>` a keyed async lock, for example allowing a single
+/// in-flight operation per user id.
+///
+/// `BorrowMap` is a handle: cloning it returns a new handle to the same map.
+pub struct BorrowMap<K, V> {
+    inner: Arc<Map<K, V>>,
+}
+
+/// Future that resolves to a `BorrowGuard` once the value stored for a key
+/// can be borrowed.
+///
+/// Returned by `BorrowMap::borrow`. The future keeps the entry in the map
+/// until it completes or is dropped.
+pub struct BorrowMapFuture<K: Eq + Hash, V> {
+    /// The entry being borrowed
+    entry: EntryRef<K, V>,
+
+    /// Key identifying the future's entry in the waiter list.
+    key: usize,
+
+    /// Where the borrow was requested
+    site: Site,
+}
+
+struct Map<K, V> {
+    entries: Mutex<Entries<K, V>>,
+}
+
+type Entries<K, V> = HashMap<K, Arc<Inner<Entry<K, V>>>>;
+
+/// A reference to an entry that evicts the entry, if unused, when dropped.
+struct EntryRef<K: Eq + Hash, V> {
+    inner: ManuallyDrop<Arc<Inner<Entry<K, V>>>>,
+}
+
+struct Entry<K, V> {
+    /// The entry's key, used to evict the entry.
+    key: K,
+
+    /// The map the entry belongs to.
+    map: Weak<Map<K, V>>,
+
+    /// The value handed out to guards. Guards only ever refer to this field.
+    value: V,
+}
+
+// ===== impl BorrowMap =====
+
+impl<K, V> BorrowMap<K, V>
+where K: Eq + Hash + Clone + Send + Sync + 'static,
+      V: Default,
+{
+    /// Create a new, empty, `BorrowMap`.
+    pub fn new() -> BorrowMap<K, V> {
+        BorrowMap {
+            inner: Arc::new(Map {
+                entries: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Returns the number of entries currently in the map.
+    ///
+    /// Only keys that are borrowed or waited on have an entry.
+    pub fn len(&self) -> usize {
+        self.inner.entries().len()
+    }
+
+    /// Returns `true` if no key is currently borrowed or waited on.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if the map contains an entry for `key`.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.inner.entries().contains_key(key)
+    }
+
+    /// Attempt to borrow the value for `key`, returning `Err` if it is already
+    /// borrowed.
+    #[track_caller]
+    pub fn try_borrow(&self, key: K) -> Result<BorrowGuard<V>, TryBorrowError> {
+        let entry = self.inner.entry(key);
+
+        entry.state.try_acquire(None, Access::Exclusive, false)?;
+
+        Ok(guard(&entry, Site::caller()))
+    }
+
+    /// Returns a future that resolves to a `BorrowGuard` once the value for
+    /// `key` can be borrowed.
+    #[track_caller]
+    pub fn borrow(&self, key: K) -> BorrowMapFuture<K, V> {
+        let entry = self.inner.entry(key);
+        let key = entry.state.insert_waiter();
+
+        BorrowMapFuture {
+            entry,
+            key,
+            site: Site::caller(),
+        }
+    }
+}
+
+impl<K, V> Default for BorrowMap<K, V>
+where K: Eq + Hash + Clone + Send + Sync + 'static,
+      V: Default,
+{
+    fn default() -> BorrowMap<K, V> {
+        BorrowMap::new()
+    }
+}
+
+impl<K, V> Clone for BorrowMap<K, V> {
+    fn clone(&self) -> BorrowMap<K, V> {
+        BorrowMap {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K, V> fmt::Debug for BorrowMap<K, V> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("BorrowMap")
+            .field("len", &self.inner.entries().len())
+            .finish()
+    }
+}
+
+unsafe impl<K: Send + Sync, V: Send> Send for BorrowMap<K, V> { }
+unsafe impl<K: Send + Sync, V: Send> Sync for BorrowMap<K, V> { }
+
+// ===== impl BorrowMapFuture =====
+
+impl<K: Eq + Hash, V> Future for BorrowMapFuture<K, V> {
+    type Item = BorrowGuard<V>;
+    type Error = BorrowError;
+
+    fn poll(&mut self) -> Poll<BorrowGuard<V>, BorrowError> {
+        try_ready!(self.entry.state.poll_acquire(self.key, Access::Exclusive, false));
+        Ok(Async::Ready(guard(&self.entry, self.site)))
+    }
+}
+
+impl<K: Eq + Hash, V> fmt::Debug for BorrowMapFuture<K, V> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("BorrowMapFuture")
+            .finish()
+    }
+}
+
+impl<K: Eq + Hash, V> Drop for BorrowMapFuture<K, V> {
+    fn drop(&mut self) {
+        self.entry.state.remove_waiter(self.key);
+    }
+}
+
+unsafe impl<K: Eq + Hash + Send + Sync, V: Send> Send for BorrowMapFuture<K, V> { }
+unsafe impl<K: Eq + Hash + Send + Sync, V: Send> Sync for BorrowMapFuture<K, V> { }
+
+// ===== impl EntryRef =====
+
+impl<K: Eq + Hash, V> ops::Deref for EntryRef<K, V> {
+    type Target = Arc<Inner<Entry<K, V>>>;
+
+    fn deref(&self) -> &Arc<Inner<Entry<K, V>>> {
+        &self.inner
+    }
+}
+
+impl<K: Eq + Hash, V> Drop for EntryRef<K, V> {
+    fn drop(&mut self) {
+        unsafe {
+            let inner = ManuallyDrop::take(&mut self.inner);
+            drop_entry_ref::<K, V>(Arc::into_raw(inner) as *const State);
+        }
+    }
+}
+
+// ===== impl Map =====
+
+impl<K: Eq + Hash + Clone, V: Default> Map<K, V> {
+    /// Returns the entry for `key`, creating it if needed.
+    fn entry(self: &Arc<Self>, key: K) -> EntryRef<K, V> {
+        let mut entries = self.entries();
+
+        if let Some(entry) = entries.get(&key) {
+            return EntryRef { inner: ManuallyDrop::new(entry.clone()) };
+        }
+
+        let waiters = Arc::new(Mutex::new(Waiters::new(Policy::ReaderPriority)));
+
+        let entry = Arc::new(Inner {
+            state: State {
+                drop_ref: drop_entry_ref::<K, V>,
+                .. State::new::<Entry<K, V>>(waiters, Policy::ReaderPriority)
+            },
+            value: UnsafeCell::new(Entry {
+                key: key.clone(),
+                map: Arc::downgrade(self),
+                value: V::default(),
+            }),
+        });
+
+        entries.insert(key, entry.clone());
+        EntryRef { inner: ManuallyDrop::new(entry) }
+    }
+}
+
+impl<K, V> Map<K, V> {
+    fn entries(&self) -> MutexGuard<'_, Entries<K, V>> {
+        self.entries.lock()
+    }
+}
+
+/// Create a guard for an entry that has been exclusively borrowed.
+fn guard<K, V>(entry: &Arc<Inner<Entry<K, V>>>, site: Site) -> BorrowGuard<V> {
+    let value_ptr = unsafe { ptr::addr_of_mut!((*entry.value.get()).value) };
+
+    BorrowGuard {
+        value_ptr,
+        handle: BorrowHandle::new(entry, Access::Exclusive, site),
+        _p: PhantomData,
+    }
+}
+
+/// Releases a reference to an entry, evicting it from the map if the map holds
+/// the only remaining reference.
+///
+/// Once only the map refers to the entry, new references can only be created
+/// from the map's reference while holding the map lock. Checking the reference
+/// count while holding the lock thus cannot race with a new borrow.
+unsafe fn drop_entry_ref<K: Eq + Hash, V>(state: *const State) {
+    let ptr = state as *const Inner<Entry<K, V>>;
+
+    // Only the immutable fields of the entry are accessed, a guard may be
+    // holding a mutable reference to the value.
+    let cell = (*ptr).value.get();
+    let map = &*ptr::addr_of!((*cell).map);
+
+    let map = match map.upgrade() {
+        Some(map) => map,
+        None => {
+            Arc::decrement_strong_count(ptr);
+            return;
+        }
+    };
+
+    let evicted = {
+        let mut entries = map.entries();
+        let key = &*ptr::addr_of!((*cell).key);
+
+        let evict = match entries.get(key) {
+            Some(entry) => Arc::as_ptr(entry) == ptr && Arc::strong_count(entry) == 2,
+            None => false,
+        };
+
+        let evicted = if evict { entries.remove(key) } else { None };
+
+        // Released while holding the lock, so that concurrent releases observe
+        // the decremented count.
+        Arc::decrement_strong_count(ptr);
+
+        evicted
+    };
+
+    // The value is dropped outside of the lock.
+    drop(evicted);
+}
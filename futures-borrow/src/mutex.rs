@@ -0,0 +1,112 @@
+//! The lock protecting the bookkeeping of borrowed values.
+//!
+//! The locked data is never left in an inconsistent state, so unlike
+//! `std::sync::Mutex`, a panic while holding the lock is not reported. With
+//! the `std` feature, the lock is a `std::sync::Mutex`. Otherwise, it is a spin
+//! lock, which is only suitable for the short critical sections of this crate.
+
+#[cfg(feature = "std")]
+use std::sync::{self, PoisonError};
+
+#[cfg(not(feature = "std"))]
+use core::{fmt, ops};
+#[cfg(not(feature = "std"))]
+use core::cell::UnsafeCell;
+#[cfg(not(feature = "std"))]
+use core::hint;
+#[cfg(not(feature = "std"))]
+use core::sync::atomic::AtomicBool;
+#[cfg(not(feature = "std"))]
+use core::sync::atomic::Ordering::{Acquire, Release, Relaxed};
+
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub(crate) struct Mutex<T> {
+    inner: sync::Mutex<T>,
+}
+
+#[cfg(feature = "std")]
+pub(crate) type MutexGuard<'a, T> = sync::MutexGuard<'a, T>;
+
+#[cfg(not(feature = "std"))]
+pub(crate) struct Mutex<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) struct MutexGuard<'a, T: 'a> {
+    mutex: &'a Mutex<T>,
+}
+
+#[cfg(feature = "std")]
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Mutex<T> {
+        Mutex {
+            inner: sync::Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Mutex<T> {
+        Mutex {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        while self.locked.compare_exchange_weak(false, true, Acquire, Relaxed).is_err() {
+            // Wait for the lock to be released before trying again, avoiding
+            // contention on the cache line.
+            while self.locked.load(Relaxed) {
+                hint::spin_loop();
+            }
+        }
+
+        MutexGuard { mutex: self }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> fmt::Debug for Mutex<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Mutex")
+            .field("locked", &self.locked.load(Relaxed))
+            .finish()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+unsafe impl<T: Send> Send for Mutex<T> { }
+#[cfg(not(feature = "std"))]
+unsafe impl<T: Send> Sync for Mutex<T> { }
+
+#[cfg(not(feature = "std"))]
+impl<'a, T> ops::Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<'a, T> ops::DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Release);
+    }
+}
@@ -0,0 +1,62 @@
+//! Detection of panics, used to poison values.
+//!
+//! With the `std` feature, a value is poisoned when a borrow is released while
+//! the thread is panicking. Without it, there is no way to tell, so poisoning
+//! is disabled unless a hook is installed with `set_panicking_hook`.
+//!
+//! The hook can be installed regardless of the feature, so that `no_std`
+//! libraries keep compiling when another crate enables `std`. It is ignored
+//! with the `std` feature.
+
+#[cfg(not(feature = "std"))]
+use core::mem;
+#[cfg(not(feature = "std"))]
+use core::sync::atomic::AtomicUsize;
+#[cfg(not(feature = "std"))]
+use core::sync::atomic::Ordering::{Acquire, Release};
+
+/// The installed hook, stored as a `fn() -> bool`. Zero if none is installed.
+#[cfg(not(feature = "std"))]
+static HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns `true` if the current thread is panicking.
+#[cfg(feature = "std")]
+pub(crate) fn panicking() -> bool {
+    ::std::thread::panicking()
+}
+
+/// Returns `true` if the current thread is panicking, as reported by the
+/// installed hook.
+#[cfg(not(feature = "std"))]
+pub(crate) fn panicking() -> bool {
+    match HOOK.load(Acquire) {
+        0 => false,
+        hook => {
+            let hook: fn() -> bool = unsafe { mem::transmute(hook) };
+            hook()
+        }
+    }
+}
+
+/// Set the function used to determine whether the current thread is
+/// panicking.
+///
+/// Without the `std` feature, a value is poisoned when an exclusive borrow is
+/// released while `hook` returns `true`. Until a hook is installed, values are
+/// never poisoned. With the `std` feature, panics are detected by the standard
+/// library and the hook is ignored.
+#[cfg(not(feature = "std"))]
+pub fn set_panicking_hook(hook: fn() -> bool) {
+    HOOK.store(hook as usize, Release);
+}
+
+/// Set the function used to determine whether the current thread is
+/// panicking.
+///
+/// Without the `std` feature, a value is poisoned when an exclusive borrow is
+/// released while `hook` returns `true`. Until a hook is installed, values are
+/// never poisoned. With the `std` feature, panics are detected by the standard
+/// library and the hook is ignored.
+#[cfg(feature = "std")]
+pub fn set_panicking_hook(_hook: fn() -> bool) {
+}
@@ -0,0 +1,361 @@
+use {Access, BorrowError, BorrowGuard, BorrowHandle, Policy, State};
+use diagnostics::Site;
+use mutex::{Mutex, MutexGuard};
+use {BORROWED_MASK, POISONED};
+use unsize::Inner;
+use waiters::Waiters;
+
+use futures::{Future, Poll, Async};
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::cell::UnsafeCell;
+use core::iter::FromIterator;
+use core::marker::PhantomData;
+use core::sync::atomic::Ordering;
+
+/// A pool of values, each of which can be borrowed by a single task at a time.
+///
+/// Acquiring from the pool returns a `BorrowGuard` to any item that is not
+/// currently borrowed. When no item is available, the task is notified once
+/// one of the guards is dropped, following the same release semantics as
+/// `Borrow`.
+///
+/// Items can be added and removed while the pool is in use. `BorrowPool` is a
+/// handle: cloning it returns a new handle to the same pool.
+///
+/// If a guard is dropped while the thread is panicking, the item is considered
+/// poisoned and is evicted from the pool instead of being handed out again.
+pub struct BorrowPool<T> {
+    inner: Arc<Pool<T>>,
+
+    /// Key identifying this handle's entry in the waiter list.
+    key: usize,
+}
+
+/// Future that resolves to a `BorrowGuard` once an item of the pool can be
+/// borrowed.
+///
+/// Returned by `BorrowPool::acquire`.
+pub struct AcquireFuture<T> {
+    inner: Arc<Pool<T>>,
+
+    /// Key identifying the future's entry in the waiter list.
+    key: usize,
+
+    /// Where the borrow was requested
+    site: Site,
+}
+
+/// Future that resolves to an item removed from the pool once one is not
+/// borrowed.
+///
+/// Returned by `BorrowPool::remove`.
+pub struct RemoveFuture<T> {
+    inner: Arc<Pool<T>>,
+
+    /// Key identifying the future's entry in the waiter list.
+    key: usize,
+}
+
+struct Pool<T> {
+    /// Tasks waiting for an item. The list is shared with the state of every
+    /// item, so releasing any of them notifies the waiters.
+    waiters: Arc<Mutex<Waiters>>,
+
+    /// The pool items. An item's value is only `None` once it has been
+    /// removed from the pool.
+    items: Mutex<Vec<Arc<Inner<Option<T>>>>>,
+}
+
+// ===== impl BorrowPool =====
+
+impl<T> BorrowPool<T> {
+    /// Create a new, empty, `BorrowPool`.
+    pub fn new() -> BorrowPool<T> {
+        let inner = Pool {
+            waiters: Arc::new(Mutex::new(Waiters::new(Policy::ReaderPriority))),
+            items: Mutex::new(Vec::new()),
+        };
+
+        BorrowPool::from_inner(Arc::new(inner))
+    }
+
+    /// Returns the number of items in the pool, including borrowed ones.
+    pub fn len(&self) -> usize {
+        self.inner.items().len()
+    }
+
+    /// Returns `true` if the pool contains no items.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Add an item to the pool, notifying waiting tasks.
+    pub fn push(&self, value: T) {
+        let item = Arc::new(Inner {
+            state: State::new::<Option<T>>(self.inner.waiters.clone(), Policy::ReaderPriority),
+            value: UnsafeCell::new(Some(value)),
+        });
+
+        let mut waiters = self.inner.waiters();
+        self.inner.items().push(item);
+        waiters.notify();
+    }
+
+    /// Attempt to borrow an item of the pool, returning `NotReady` if all
+    /// items are currently borrowed.
+    ///
+    /// When `NotReady` is returned, the current task will be notified once an
+    /// item is released or added to the pool.
+    #[track_caller]
+    pub fn poll_acquire(&mut self) -> Async<BorrowGuard<T>> {
+        self.inner.poll_acquire(self.key, Site::caller())
+    }
+
+    /// Attempt to borrow an item of the pool, returning `None` if all items
+    /// are currently borrowed.
+    #[track_caller]
+    pub fn try_acquire(&self) -> Option<BorrowGuard<T>> {
+        let _waiters = self.inner.waiters();
+        self.inner.acquire(Site::caller())
+    }
+
+    /// Returns a future that resolves to a `BorrowGuard` once an item of the
+    /// pool can be borrowed.
+    #[track_caller]
+    pub fn acquire(&self) -> AcquireFuture<T> {
+        AcquireFuture {
+            inner: self.inner.clone(),
+            key: self.inner.waiters().insert(),
+            site: Site::caller(),
+        }
+    }
+
+    /// Attempt to remove an item from the pool, returning `NotReady` if all
+    /// items are currently borrowed.
+    ///
+    /// When `NotReady` is returned, the current task will be notified once an
+    /// item is released or added to the pool.
+    pub fn poll_remove(&mut self) -> Async<T> {
+        self.inner.poll_remove(self.key)
+    }
+
+    /// Attempt to remove an item from the pool, returning `None` if all items
+    /// are currently borrowed.
+    pub fn try_remove(&self) -> Option<T> {
+        let _waiters = self.inner.waiters();
+        self.inner.remove()
+    }
+
+    /// Returns a future that resolves to an item removed from the pool once
+    /// one is not borrowed.
+    pub fn remove(&self) -> RemoveFuture<T> {
+        RemoveFuture {
+            inner: self.inner.clone(),
+            key: self.inner.waiters().insert(),
+        }
+    }
+
+    fn from_inner(inner: Arc<Pool<T>>) -> BorrowPool<T> {
+        let key = inner.waiters().insert();
+        BorrowPool { inner, key }
+    }
+}
+
+impl<T> Default for BorrowPool<T> {
+    fn default() -> BorrowPool<T> {
+        BorrowPool::new()
+    }
+}
+
+impl<T> FromIterator<T> for BorrowPool<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> BorrowPool<T> {
+        let pool = BorrowPool::new();
+
+        for value in iter {
+            pool.push(value);
+        }
+
+        pool
+    }
+}
+
+impl<T> Clone for BorrowPool<T> {
+    fn clone(&self) -> BorrowPool<T> {
+        BorrowPool::from_inner(self.inner.clone())
+    }
+}
+
+impl<T> fmt::Debug for BorrowPool<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("BorrowPool")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl<T> Drop for BorrowPool<T> {
+    fn drop(&mut self) {
+        self.inner.waiters().remove(self.key);
+    }
+}
+
+unsafe impl<T: Send> Send for BorrowPool<T> { }
+unsafe impl<T: Send> Sync for BorrowPool<T> { }
+
+// ===== impl AcquireFuture =====
+
+impl<T> Future for AcquireFuture<T> {
+    type Item = BorrowGuard<T>;
+
+    /// Poisoned items are evicted from the pool, so acquiring cannot fail. The
+    /// error type matches `BorrowFuture`.
+    type Error = BorrowError;
+
+    fn poll(&mut self) -> Poll<BorrowGuard<T>, BorrowError> {
+        Ok(self.inner.poll_acquire(self.key, self.site))
+    }
+}
+
+impl<T> fmt::Debug for AcquireFuture<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("AcquireFuture")
+            .finish()
+    }
+}
+
+impl<T> Drop for AcquireFuture<T> {
+    fn drop(&mut self) {
+        self.inner.waiters().remove(self.key);
+    }
+}
+
+unsafe impl<T: Send> Send for AcquireFuture<T> { }
+unsafe impl<T: Send> Sync for AcquireFuture<T> { }
+
+// ===== impl RemoveFuture =====
+
+impl<T> Future for RemoveFuture<T> {
+    type Item = T;
+
+    /// Removing an item cannot fail, the error type matches `BorrowFuture`.
+    type Error = BorrowError;
+
+    fn poll(&mut self) -> Poll<T, BorrowError> {
+        Ok(self.inner.poll_remove(self.key))
+    }
+}
+
+impl<T> fmt::Debug for RemoveFuture<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("RemoveFuture")
+            .finish()
+    }
+}
+
+impl<T> Drop for RemoveFuture<T> {
+    fn drop(&mut self) {
+        self.inner.waiters().remove(self.key);
+    }
+}
+
+unsafe impl<T: Send> Send for RemoveFuture<T> { }
+unsafe impl<T: Send> Sync for RemoveFuture<T> { }
+
+// ===== impl Pool =====
+
+impl<T> Pool<T> {
+    fn poll_acquire(&self, key: usize, site: Site) -> Async<BorrowGuard<T>> {
+        // Releasing an item requires the waiter lock in order to notify
+        // waiters, so holding it while scanning the items ensures that the
+        // release cannot be missed.
+        let mut waiters = self.waiters();
+
+        match self.acquire(site) {
+            Some(guard) => {
+                waiters.dequeue(key);
+                Async::Ready(guard)
+            }
+            None => {
+                waiters.register(key, false);
+                Async::NotReady
+            }
+        }
+    }
+
+    fn poll_remove(&self, key: usize) -> Async<T> {
+        let mut waiters = self.waiters();
+
+        match self.remove() {
+            Some(value) => {
+                waiters.dequeue(key);
+                Async::Ready(value)
+            }
+            None => {
+                waiters.register(key, false);
+                Async::NotReady
+            }
+        }
+    }
+
+    /// Borrow the first available item. Must be called with the waiter lock
+    /// held.
+    fn acquire(&self, site: Site) -> Option<BorrowGuard<T>> {
+        let mut items = self.items();
+        self.evict_poisoned(&mut items);
+
+        for item in items.iter() {
+            if item.state.acquire(Access::Exclusive, false).is_err() {
+                continue;
+            }
+
+            let value_ptr = unsafe {
+                (*item.value.get()).as_mut().unwrap() as *mut T
+            };
+
+            return Some(BorrowGuard {
+                value_ptr,
+                handle: BorrowHandle::new(item, Access::Exclusive, site),
+                _p: PhantomData,
+            });
+        }
+
+        None
+    }
+
+    /// Remove the first available item. Must be called with the waiter lock
+    /// held.
+    fn remove(&self) -> Option<T> {
+        let mut items = self.items();
+        self.evict_poisoned(&mut items);
+
+        // Items are only borrowed while holding the `items` lock, so an item
+        // that is not borrowed now cannot become borrowed.
+        let pos = items.iter()
+            .position(|item| item.state.borrowed.load(Ordering::Acquire) & BORROWED_MASK == 0)?;
+
+        let item = items.swap_remove(pos);
+
+        // A guard that was just dropped may still hold a reference to the
+        // item, so the value is taken out instead of unwrapping the `Arc`.
+        unsafe { (*item.value.get()).take() }
+    }
+
+    /// Remove poisoned items from the pool.
+    fn evict_poisoned(&self, items: &mut Vec<Arc<Inner<Option<T>>>>) {
+        items.retain(|item| {
+            let curr = item.state.borrowed.load(Ordering::Acquire);
+            curr & BORROWED_MASK != 0 || curr & POISONED == 0
+        });
+    }
+
+    fn waiters(&self) -> MutexGuard<'_, Waiters> {
+        self.waiters.lock()
+    }
+
+    fn items(&self) -> MutexGuard<'_, Vec<Arc<Inner<Option<T>>>>> {
+        self.items.lock()
+    }
+}
@@ -0,0 +1,312 @@
+use {BorrowError, Kind, Policy, TryBorrowError};
+use mutex::{Mutex, MutexGuard};
+use panicking;
+use waiters::Waiters;
+
+use futures::{Future, Poll, Async};
+
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize};
+use core::sync::atomic::Ordering::{Acquire, Release, Relaxed};
+
+/// A counting semaphore, notifying waiting tasks once permits are released.
+///
+/// Each acquisition requests a number of permits and resolves to a `Permit`
+/// holding them until it is dropped. Waiting tasks acquire permits in the
+/// order in which they started waiting, so a task requesting many permits is
+/// not starved by tasks requesting fewer.
+///
+/// The flip side is that a request for more permits than the semaphore holds
+/// waits until enough permits are added with `add_permits`, and every task
+/// that starts waiting after it is blocked in the meantime. Requests should
+/// not exceed the number of permits the semaphore is created with.
+///
+/// `Semaphore` is a handle: cloning it returns a new handle to the same
+/// semaphore.
+///
+/// If a `Permit` is dropped while the thread is panicking, the semaphore is
+/// considered poisoned and acquiring permits fails until `clear_poison` is
+/// called, following the same conventions as `Borrow`.
+pub struct Semaphore {
+    inner: Arc<Inner>,
+
+    /// Key identifying this handle's entry in the waiter list.
+    key: usize,
+}
+
+/// Permits acquired from a `Semaphore`.
+///
+/// When the value is dropped, the permits are returned to the semaphore,
+/// notifying any pending tasks.
+pub struct Permit {
+    inner: Arc<Inner>,
+
+    /// Number of permits held
+    permits: usize,
+}
+
+/// Future that resolves to a `Permit` once enough permits are available.
+///
+/// Returned by `Semaphore::acquire`.
+pub struct AcquirePermits {
+    inner: Arc<Inner>,
+
+    /// Key identifying the future's entry in the waiter list.
+    key: usize,
+
+    /// Number of permits to acquire
+    permits: usize,
+}
+
+struct Inner {
+    /// Number of available permits. Permits are only taken while holding the
+    /// waiter lock.
+    permits: AtomicUsize,
+
+    /// `true` once a permit was released while the thread was panicking.
+    poisoned: AtomicBool,
+
+    /// Tasks waiting for permits, in FIFO order.
+    waiters: Mutex<Waiters>,
+}
+
+// ===== impl Semaphore =====
+
+impl Semaphore {
+    /// Create a new `Semaphore` with `permits` available permits.
+    pub fn new(permits: usize) -> Semaphore {
+        let inner = Inner {
+            permits: AtomicUsize::new(permits),
+            poisoned: AtomicBool::new(false),
+            waiters: Mutex::new(Waiters::new(Policy::Fifo)),
+        };
+
+        Semaphore::from_inner(Arc::new(inner))
+    }
+
+    /// Returns the number of permits currently available.
+    pub fn available_permits(&self) -> usize {
+        self.inner.permits.load(Acquire)
+    }
+
+    /// Add `n` permits to the semaphore, notifying waiting tasks.
+    pub fn add_permits(&self, n: usize) {
+        self.inner.release(n);
+    }
+
+    /// Attempt to acquire `n` permits, returning `NotReady` if not enough
+    /// permits are available.
+    ///
+    /// When `NotReady` is returned, the current task will be notified once
+    /// enough permits may be available. A waiting `Semaphore` handle keeps its
+    /// place in the queue until it acquires the permits or is dropped. If `n`
+    /// exceeds the number of permits held by the semaphore, this only happens
+    /// once enough permits are added.
+    ///
+    /// Returns `Err` if the semaphore is poisoned.
+    pub fn poll_acquire(&mut self, n: usize) -> Poll<Permit, BorrowError> {
+        self.inner.poll_acquire(self.key, n)
+    }
+
+    /// Attempt to acquire `n` permits without waiting.
+    ///
+    /// Fails if not enough permits are available or if tasks are already
+    /// waiting for permits, in which case acquiring would skip ahead of them.
+    pub fn try_acquire(&self, n: usize) -> Result<Permit, TryBorrowError> {
+        let waiters = self.inner.waiters();
+
+        if self.inner.is_poisoned() {
+            return Err(TryBorrowError::new(true));
+        }
+
+        if !waiters.is_next(None, false) || !self.inner.take(n) {
+            return Err(TryBorrowError::new(false));
+        }
+
+        Ok(Permit::new(&self.inner, n))
+    }
+
+    /// Returns a future that resolves to a `Permit` once `n` permits are
+    /// available.
+    ///
+    /// As with `poll_acquire`, the future blocks the tasks waiting after it
+    /// until it completes or is dropped.
+    pub fn acquire(&self, n: usize) -> AcquirePermits {
+        AcquirePermits {
+            inner: self.inner.clone(),
+            key: self.inner.waiters().insert(),
+            permits: n,
+        }
+    }
+
+    /// Returns `true` if the semaphore is poisoned.
+    ///
+    /// The semaphore becomes poisoned when a `Permit` is dropped while the
+    /// thread is panicking.
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    /// Clear the poisoned state, allowing permits to be acquired again.
+    pub fn clear_poison(&self) {
+        self.inner.poisoned.store(false, Release);
+    }
+
+    fn from_inner(inner: Arc<Inner>) -> Semaphore {
+        let key = inner.waiters().insert();
+        Semaphore { inner, key }
+    }
+}
+
+impl Clone for Semaphore {
+    fn clone(&self) -> Semaphore {
+        Semaphore::from_inner(self.inner.clone())
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Semaphore")
+            .field("available_permits", &self.available_permits())
+            .field("poisoned", &self.is_poisoned())
+            .finish()
+    }
+}
+
+impl Drop for Semaphore {
+    fn drop(&mut self) {
+        self.inner.remove_waiter(self.key);
+    }
+}
+
+// ===== impl Permit =====
+
+impl Permit {
+    fn new(inner: &Arc<Inner>, permits: usize) -> Permit {
+        Permit {
+            inner: inner.clone(),
+            permits,
+        }
+    }
+
+    /// Returns the number of permits held.
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+}
+
+impl fmt::Debug for Permit {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Permit")
+            .field("permits", &self.permits)
+            .finish()
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if panicking::panicking() {
+            self.inner.poisoned.store(true, Relaxed);
+        }
+
+        self.inner.release(self.permits);
+    }
+}
+
+// ===== impl AcquirePermits =====
+
+impl Future for AcquirePermits {
+    type Item = Permit;
+    type Error = BorrowError;
+
+    fn poll(&mut self) -> Poll<Permit, BorrowError> {
+        self.inner.poll_acquire(self.key, self.permits)
+    }
+}
+
+impl fmt::Debug for AcquirePermits {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("AcquirePermits")
+            .field("permits", &self.permits)
+            .finish()
+    }
+}
+
+impl Drop for AcquirePermits {
+    fn drop(&mut self) {
+        self.inner.remove_waiter(self.key);
+    }
+}
+
+// ===== impl Inner =====
+
+impl Inner {
+    fn poll_acquire(self: &Arc<Self>, key: usize, n: usize) -> Poll<Permit, BorrowError> {
+        // Releasing permits requires the waiter lock in order to notify
+        // waiters, so holding it while taking permits ensures that the release
+        // cannot be missed.
+        let mut waiters = self.waiters();
+
+        if self.is_poisoned() {
+            // Let the following waiter observe the poisoning as well.
+            waiters.dequeue(key);
+            waiters.notify();
+
+            return Err(BorrowError::new(Kind::Poisoned));
+        }
+
+        if waiters.is_next(Some(key), false) && self.take(n) {
+            waiters.dequeue(key);
+
+            if self.permits.load(Acquire) > 0 {
+                // Only the front of the queue was notified, the remaining
+                // permits may satisfy the next waiter.
+                waiters.notify();
+            }
+
+            return Ok(Async::Ready(Permit::new(self, n)));
+        }
+
+        waiters.register(key, false);
+        Ok(Async::NotReady)
+    }
+
+    /// Take `n` permits, returning `false` if not enough are available. Must
+    /// be called with the waiter lock held.
+    fn take(&self, n: usize) -> bool {
+        let curr = self.permits.load(Acquire);
+
+        if curr < n {
+            return false;
+        }
+
+        self.permits.store(curr - n, Release);
+        true
+    }
+
+    /// Return `n` permits to the semaphore, notifying waiters.
+    fn release(&self, n: usize) {
+        let mut waiters = self.waiters();
+        self.permits.fetch_add(n, Release);
+        waiters.notify();
+    }
+
+    /// Release the waiter entry identified by `key`.
+    fn remove_waiter(&self, key: usize) {
+        let mut waiters = self.waiters();
+
+        if waiters.remove(key) {
+            // The waiter was next in line, let the following one through.
+            waiters.notify();
+        }
+    }
+
+    fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Acquire)
+    }
+
+    fn waiters(&self) -> MutexGuard<'_, Waiters> {
+        self.waiters.lock()
+    }
+}
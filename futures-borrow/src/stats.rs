@@ -0,0 +1,186 @@
+//! Contention metrics of a value.
+//!
+//! Metrics are only collected when the `stats` feature is enabled, otherwise
+//! all of the types in this module are zero-sized and the operations are
+//! no-ops.
+
+#[cfg(feature = "stats")]
+use std::sync::atomic::AtomicU64;
+#[cfg(feature = "stats")]
+use std::sync::atomic::Ordering::Relaxed;
+#[cfg(feature = "stats")]
+use std::time::{Duration, Instant};
+
+/// Counters tracking how a value is borrowed.
+#[derive(Debug)]
+pub(crate) struct Stats {
+    #[cfg(feature = "stats")]
+    borrows: AtomicU64,
+
+    #[cfg(feature = "stats")]
+    failed_try_borrows: AtomicU64,
+
+    #[cfg(feature = "stats")]
+    not_ready: AtomicU64,
+
+    /// Cumulative hold time, in nanoseconds
+    #[cfg(feature = "stats")]
+    hold_time: AtomicU64,
+
+    /// Longest wait, in nanoseconds
+    #[cfg(feature = "stats")]
+    max_wait: AtomicU64,
+}
+
+/// The instant at which a borrow was acquired or a wait started.
+#[derive(Debug, Default)]
+pub(crate) struct Timestamp {
+    #[cfg(feature = "stats")]
+    instant: Option<Instant>,
+}
+
+/// A snapshot of the contention metrics of a `Borrow`.
+///
+/// Returned by `Borrow::stats`.
+#[cfg(feature = "stats")]
+#[derive(Debug, Clone, Copy)]
+pub struct BorrowStats {
+    borrows: u64,
+    failed_try_borrows: u64,
+    not_ready: u64,
+    hold_time: Duration,
+    max_wait: Duration,
+}
+
+#[cfg(feature = "stats")]
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            borrows: AtomicU64::new(0),
+            failed_try_borrows: AtomicU64::new(0),
+            not_ready: AtomicU64::new(0),
+            hold_time: AtomicU64::new(0),
+            max_wait: AtomicU64::new(0),
+        }
+    }
+
+    /// A borrow was acquired.
+    pub fn borrowed(&self) {
+        self.borrows.fetch_add(1, Relaxed);
+    }
+
+    /// A `try_borrow` call failed.
+    pub fn try_borrow_failed(&self) {
+        self.failed_try_borrows.fetch_add(1, Relaxed);
+    }
+
+    /// A `poll_borrow` call returned `NotReady`.
+    pub fn not_ready(&self) {
+        self.not_ready.fetch_add(1, Relaxed);
+    }
+
+    /// A borrow acquired at `acquired` was released.
+    pub fn released(&self, acquired: &Timestamp) {
+        if let Some(instant) = acquired.instant {
+            self.hold_time.fetch_add(nanos(instant.elapsed()), Relaxed);
+        }
+    }
+
+    /// A wait that started at `since` completed.
+    pub fn waited(&self, since: &Timestamp) {
+        if let Some(instant) = since.instant {
+            self.max_wait.fetch_max(nanos(instant.elapsed()), Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> BorrowStats {
+        BorrowStats {
+            borrows: self.borrows.load(Relaxed),
+            failed_try_borrows: self.failed_try_borrows.load(Relaxed),
+            not_ready: self.not_ready.load(Relaxed),
+            hold_time: Duration::from_nanos(self.hold_time.load(Relaxed)),
+            max_wait: Duration::from_nanos(self.max_wait.load(Relaxed)),
+        }
+    }
+}
+
+#[cfg(not(feature = "stats"))]
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {}
+    }
+
+    pub fn borrowed(&self) {
+    }
+
+    pub fn try_borrow_failed(&self) {
+    }
+
+    pub fn not_ready(&self) {
+    }
+
+    pub fn released(&self, _: &Timestamp) {
+    }
+
+    pub fn waited(&self, _: &Timestamp) {
+    }
+}
+
+impl Timestamp {
+    /// Returns a timestamp for the current instant.
+    pub fn now() -> Timestamp {
+        Timestamp {
+            #[cfg(feature = "stats")]
+            instant: Some(Instant::now()),
+        }
+    }
+
+    /// Set the timestamp to the current instant, unless it is already set.
+    pub fn start(&mut self) {
+        #[cfg(feature = "stats")]
+        {
+            if self.instant.is_none() {
+                self.instant = Some(Instant::now());
+            }
+        }
+    }
+}
+
+#[cfg(feature = "stats")]
+impl BorrowStats {
+    /// Returns the number of borrows acquired.
+    pub fn borrows(&self) -> u64 {
+        self.borrows
+    }
+
+    /// Returns the number of `try_borrow` calls, of any kind, that failed.
+    pub fn failed_try_borrows(&self) -> u64 {
+        self.failed_try_borrows
+    }
+
+    /// Returns the number of times a `poll_borrow` call, of any kind, returned
+    /// `NotReady`.
+    pub fn not_ready(&self) -> u64 {
+        self.not_ready
+    }
+
+    /// Returns the total time that borrows have been held for.
+    ///
+    /// Only released borrows are accounted for. Each shared borrow counts
+    /// separately.
+    pub fn hold_time(&self) -> Duration {
+        self.hold_time
+    }
+
+    /// Returns the longest time a task waited before acquiring a borrow.
+    pub fn max_wait(&self) -> Duration {
+        self.max_wait
+    }
+}
+
+#[cfg(feature = "stats")]
+fn nanos(dur: Duration) -> u64 {
+    dur.as_secs()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(u64::from(dur.subsec_nanos()))
+}
@@ -0,0 +1,349 @@
+use Policy;
+use stats::Timestamp;
+
+use futures::task::{self, Task};
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::mem;
+
+/// Tracks the tasks waiting to borrow a value.
+///
+/// Each handle that may wait on the borrow (`Borrow`, `BorrowFuture`, ...)
+/// owns an entry in the waiter list. An entry stays allocated for the lifetime
+/// of the handle and holds the tasks to notify once the borrow is released. A
+/// handle may be polled from more than one task, so every task is tracked.
+///
+/// With the `Fifo` policy, pending entries are additionally tracked in FIFO
+/// order and only the entries at the front of the queue may acquire the
+/// borrow. With the `WriterPriority` policy, shared borrows are held back while
+/// an entry is waiting for an exclusive borrow.
+#[derive(Debug)]
+pub(crate) struct Waiters {
+    /// Waiter storage
+    entries: Vec<Entry>,
+
+    /// Index of the first vacant entry
+    next: usize,
+
+    /// Pending waiters, in arrival order. Only used with the `Fifo` policy.
+    queue: VecDeque<usize>,
+
+    /// Number of pending waiters waiting for an exclusive borrow.
+    writers: usize,
+
+    /// Determines which waiters may acquire the borrow.
+    policy: Policy,
+}
+
+#[derive(Debug)]
+enum Entry {
+    Vacant(usize),
+    Occupied(Waiter),
+}
+
+#[derive(Debug)]
+struct Waiter {
+    /// Tasks to notify
+    tasks: Vec<Task>,
+
+    /// `true` if the waiter is waiting for a shared borrow.
+    shared: bool,
+
+    /// `true` while the waiter is registered to acquire the borrow.
+    pending: bool,
+
+    /// `true` if the waiter is in the FIFO queue.
+    queued: bool,
+
+    /// When the waiter started waiting, only tracked with the `stats` feature.
+    waiting_since: Timestamp,
+
+    /// When set, the waiter is only notified once the value's version differs
+    /// from this one.
+    changed_since: Option<usize>,
+}
+
+impl Waiters {
+    pub fn new(policy: Policy) -> Waiters {
+        Waiters {
+            entries: Vec::new(),
+            next: 0,
+            queue: VecDeque::new(),
+            writers: 0,
+            policy,
+        }
+    }
+
+    /// Allocate a new waiter entry, returning its key.
+    pub fn insert(&mut self) -> usize {
+        let key = self.next;
+        let waiter = Entry::Occupied(Waiter {
+            tasks: Vec::new(),
+            shared: false,
+            pending: false,
+            queued: false,
+            waiting_since: Timestamp::default(),
+            changed_since: None,
+        });
+
+        if key == self.entries.len() {
+            self.entries.push(waiter);
+            self.next = key + 1;
+        } else {
+            match mem::replace(&mut self.entries[key], waiter) {
+                Entry::Vacant(next) => self.next = next,
+                Entry::Occupied(_) => unreachable!(),
+            }
+        }
+
+        key
+    }
+
+    /// Release the waiter entry identified by `key`.
+    ///
+    /// Returns `true` if the entry was at the front of the queue, in which case
+    /// the caller is responsible for notifying the next waiter.
+    pub fn remove(&mut self, key: usize) -> bool {
+        let is_first = self.queue.front() == Some(&key);
+
+        self.dequeue(key);
+        self.entries[key] = Entry::Vacant(self.next);
+        self.next = key;
+
+        is_first
+    }
+
+    /// Track the current task, notifying it once the borrow is released.
+    ///
+    /// With the `Fifo` policy, the waiter is pushed to the back of the queue
+    /// if it is not already queued.
+    pub fn register(&mut self, key: usize, shared: bool) {
+        let fifo = self.policy == Policy::Fifo;
+
+        let (was_writer, queued) = {
+            let waiter = self.waiter_mut(key);
+
+            if !waiter.tasks.iter().any(|task| task.will_notify_current()) {
+                waiter.tasks.push(task::current());
+            }
+
+            let was_writer = waiter.pending && !waiter.shared;
+            let queued = waiter.queued;
+
+            waiter.shared = shared;
+            waiter.pending = true;
+            waiter.queued = queued || fifo;
+            waiter.waiting_since.start();
+            waiter.changed_since = None;
+
+            (was_writer, queued)
+        };
+
+        match (was_writer, shared) {
+            (false, false) => self.writers += 1,
+            (true, true) => self.writers -= 1,
+            _ => {}
+        }
+
+        if fifo && !queued {
+            self.queue.push_back(key);
+        }
+    }
+
+    /// Register a waiter upgrading an upgradeable borrow to an exclusive one.
+    ///
+    /// The waiter counts as an exclusive waiter, but goes to the front of the
+    /// queue: it already holds the upgradeable borrow, so no exclusive waiter
+    /// can acquire the borrow before it.
+    pub fn register_upgrade(&mut self, key: usize) {
+        self.register(key, false);
+
+        if self.policy == Policy::Fifo && self.queue.front() != Some(&key) {
+            self.queue.retain(|k| *k != key);
+            self.queue.push_front(key);
+        }
+    }
+
+    /// Track the current task, notifying it once the borrow is released.
+    ///
+    /// Unlike `register`, the waiter does not take a place in the queue. This
+    /// is used by waiters that only observe the borrow without acquiring it.
+    pub fn watch(&mut self, key: usize) {
+        let waiter = self.waiter_mut(key);
+
+        if !waiter.tasks.iter().any(|task| task.will_notify_current()) {
+            waiter.tasks.push(task::current());
+        }
+
+        waiter.changed_since = None;
+    }
+
+    /// Track the current task, notifying it once the value's version differs
+    /// from `version`.
+    ///
+    /// Like `watch`, the waiter does not take a place in the queue.
+    pub fn watch_changes(&mut self, key: usize, version: usize) {
+        self.watch(key);
+        self.waiter_mut(key).changed_since = Some(version);
+    }
+
+    /// Called once the waiter has acquired the borrow or stopped waiting.
+    ///
+    /// Returns the instant at which the waiter started waiting.
+    pub fn dequeue(&mut self, key: usize) -> Timestamp {
+        let (queued, writer, since) = {
+            let waiter = self.waiter_mut(key);
+            waiter.tasks.clear();
+
+            let writer = waiter.pending && !waiter.shared;
+            waiter.pending = false;
+
+            let queued = mem::replace(&mut waiter.queued, false);
+            (queued, writer, mem::take(&mut waiter.waiting_since))
+        };
+
+        if queued {
+            self.queue.retain(|k| *k != key);
+        }
+
+        if writer {
+            self.writers -= 1;
+
+            if self.writers == 0 && self.policy == Policy::WriterPriority {
+                // Shared waiters were held back by this waiter.
+                self.notify_readers();
+            }
+        }
+
+        since
+    }
+
+    /// Returns `true` if the waiter identified by `key` may attempt to acquire
+    /// the borrow.
+    ///
+    /// With the `ReaderPriority` policy, any waiter may attempt to acquire.
+    /// With `WriterPriority`, a shared waiter may only attempt to acquire if no
+    /// waiter is waiting for an exclusive borrow. With `Fifo`, an exclusive
+    /// waiter must be at the front of the queue and a shared waiter must only
+    /// be preceded by other shared waiters.
+    pub fn is_next(&self, key: Option<usize>, shared: bool) -> bool {
+        match self.policy {
+            Policy::ReaderPriority => return true,
+            Policy::WriterPriority => return !shared || self.writers == 0,
+            Policy::Fifo => {}
+        }
+
+        for &k in &self.queue {
+            if Some(k) == key {
+                return true;
+            }
+
+            if !shared || !self.waiter(k).shared {
+                return false;
+            }
+        }
+
+        // Not queued yet, only acquire if there is nobody to skip.
+        self.queue.is_empty()
+    }
+
+    /// Notify waiters that the borrow has been released.
+    ///
+    /// With the `ReaderPriority` policy, all waiters are notified. With
+    /// `WriterPriority`, shared waiters are skipped while an exclusive waiter
+    /// is pending. With `Fifo`, only the waiter at the front of the queue is
+    /// notified, along with any shared waiters directly following it when it
+    /// is itself a shared waiter. Waiters that are not queued are always
+    /// notified, unless they are watching changes or are held back readers.
+    pub fn notify(&mut self) {
+        let hold_readers = self.policy == Policy::WriterPriority && self.writers > 0;
+
+        for entry in &mut self.entries {
+            if let Entry::Occupied(ref mut waiter) = *entry {
+                if hold_readers && waiter.pending && waiter.shared {
+                    continue;
+                }
+
+                if !waiter.queued && waiter.changed_since.is_none() {
+                    for task in waiter.tasks.drain(..) {
+                        task.notify();
+                    }
+                }
+            }
+        }
+
+        if self.policy != Policy::Fifo {
+            return;
+        }
+
+        let mut first = true;
+
+        for &key in &self.queue {
+            let waiter = match self.entries[key] {
+                Entry::Occupied(ref waiter) => waiter,
+                Entry::Vacant(_) => unreachable!(),
+            };
+
+            if !first && !waiter.shared {
+                break;
+            }
+
+            // Queued waiters keep their tasks, they are notified again if
+            // they are still first in line after the next release.
+            for task in &waiter.tasks {
+                task.notify();
+            }
+
+            if !waiter.shared {
+                break;
+            }
+
+            first = false;
+        }
+    }
+
+    /// Notify the waiters watching changes that observed a version other than
+    /// `version`.
+    pub fn notify_changed(&mut self, version: usize) {
+        for entry in &mut self.entries {
+            if let Entry::Occupied(ref mut waiter) = *entry {
+                match waiter.changed_since {
+                    Some(v) if v != version => {}
+                    _ => continue,
+                }
+
+                for task in waiter.tasks.drain(..) {
+                    task.notify();
+                }
+            }
+        }
+    }
+
+    /// Notify the waiters waiting for a shared borrow.
+    fn notify_readers(&mut self) {
+        for entry in &mut self.entries {
+            if let Entry::Occupied(ref mut waiter) = *entry {
+                if waiter.pending && waiter.shared {
+                    for task in waiter.tasks.drain(..) {
+                        task.notify();
+                    }
+                }
+            }
+        }
+    }
+
+    fn waiter(&self, key: usize) -> &Waiter {
+        match self.entries[key] {
+            Entry::Occupied(ref waiter) => waiter,
+            Entry::Vacant(_) => unreachable!(),
+        }
+    }
+
+    fn waiter_mut(&mut self, key: usize) -> &mut Waiter {
+        match self.entries[key] {
+            Entry::Occupied(ref mut waiter) => waiter,
+            Entry::Vacant(_) => unreachable!(),
+        }
+    }
+}
@@ -2,8 +2,21 @@ extern crate futures;
 extern crate futures_borrow;
 extern crate futures_test;
 
+use futures::{future, stream, Async, Future, Sink, Stream};
+use futures::sync::oneshot;
 use futures_borrow::*;
-use futures_test::Harness;
+use futures_test::{Harness, MockClock};
+
+use std::cell::RefCell;
+use std::panic;
+use std::time::Duration;
+
+fn ready<T>(res: Async<T>) -> T {
+    match res {
+        Async::Ready(v) => v,
+        Async::NotReady => panic!("not ready"),
+    }
+}
 
 #[test]
 fn test_basic_borrow() {
@@ -65,3 +78,911 @@ fn test_borrow_map() {
 
     assert_eq!(b[0], "hello-world");
 }
+
+#[test]
+fn test_shared_borrow() {
+    let mut s = Borrow::new("hello".to_string());
+
+    let b1 = s.try_borrow_shared().unwrap();
+    let b2 = s.try_borrow_shared().unwrap();
+
+    assert_eq!(*b1, "hello");
+    assert_eq!(*b2, "hello");
+
+    // Shared borrows exclude exclusive borrows
+    assert!(s.try_borrow().is_err());
+    assert!(!s.is_ready());
+    assert!(s.is_ready_shared());
+
+    {
+        let mut borrow = Harness::poll_fn(|| s.poll_borrow());
+
+        // Not ready
+        assert!(!borrow.poll().unwrap().is_ready());
+
+        // Dropping a single shared borrow does not notify
+        drop(b1);
+        assert!(!borrow.is_notified());
+
+        // Dropping the last one does
+        drop(b2);
+        assert!(borrow.is_notified());
+
+        let mut b = ready(borrow.poll().unwrap());
+        b.push_str("-world");
+    }
+
+    let b = s.try_borrow_shared().unwrap();
+    assert_eq!(*b, "hello-world");
+}
+
+#[test]
+fn test_shared_borrow_waits_for_exclusive() {
+    let mut s = Borrow::new(1);
+
+    let mut b = s.try_borrow().unwrap();
+
+    assert!(s.try_borrow_shared().is_err());
+    assert!(!s.is_ready_shared());
+
+    let mut shared = Harness::poll_fn(|| s.poll_borrow_shared());
+    assert!(!shared.poll().unwrap().is_ready());
+
+    *b += 1;
+    drop(b);
+
+    assert!(shared.is_notified());
+    assert_eq!(*ready(shared.poll().unwrap()), 2);
+}
+
+#[test]
+fn test_notify_all_waiting_tasks() {
+    let s = RefCell::new(Borrow::new(1));
+
+    let b = s.borrow().try_borrow().unwrap();
+
+    let mut t1 = Harness::poll_fn(|| s.borrow_mut().poll_borrow().map(|r| r.map(|_| ())));
+    let mut t2 = Harness::poll_fn(|| s.borrow_mut().poll_ready());
+
+    assert!(!t1.poll().unwrap().is_ready());
+    assert!(!t2.poll().unwrap().is_ready());
+
+    drop(b);
+
+    // Both tasks are notified
+    assert!(t1.is_notified());
+    assert!(t2.is_notified());
+
+    assert!(t1.poll().unwrap().is_ready());
+    assert!(t2.poll().unwrap().is_ready());
+}
+
+#[test]
+fn test_fair_borrow() {
+    let s = RefCell::new(Borrow::new_fair(1));
+
+    let b = s.borrow().try_borrow().unwrap();
+
+    let mut waiter = Harness::poll_fn(|| {
+        s.borrow_mut().poll_borrow().map(|r| r.map(|b| *b))
+    });
+
+    assert!(!waiter.poll().unwrap().is_ready());
+
+    drop(b);
+    assert!(waiter.is_notified());
+
+    // The waiting task has not acquired the borrow yet, new borrows from other
+    // handles cannot skip ahead of it.
+    let other = s.borrow().clone();
+    assert!(other.try_borrow().is_err());
+    assert!(other.try_borrow_shared().is_err());
+
+    assert_eq!(ready(waiter.poll().unwrap()), 1);
+
+    // Once the waiter is done, the value can be borrowed again
+    assert!(s.borrow().try_borrow().is_ok());
+}
+
+#[test]
+fn test_reader_priority_policy() {
+    let s = Borrow::builder().policy(Policy::ReaderPriority).build(1);
+
+    let r1 = s.try_borrow_shared().unwrap();
+
+    let mut writer = Harness::new(s.borrow());
+    assert!(!writer.poll().unwrap().is_ready());
+
+    // Readers are not held back by the waiting writer
+    let r2 = s.try_borrow_shared().unwrap();
+
+    drop(r1);
+    assert!(!writer.is_notified());
+
+    drop(r2);
+    assert!(writer.is_notified());
+    assert!(writer.poll().unwrap().is_ready());
+}
+
+#[test]
+fn test_writer_priority_policy() {
+    let s = Borrow::builder().policy(Policy::WriterPriority).build(1);
+
+    let r1 = s.try_borrow_shared().unwrap();
+
+    let mut writer = Harness::new(s.borrow());
+    assert!(!writer.poll().unwrap().is_ready());
+
+    // New readers are held back while the writer waits
+    assert!(s.try_borrow_shared().is_err());
+
+    let mut other = s.clone();
+    let mut reader = Harness::poll_fn(|| other.poll_borrow_shared());
+    assert!(!reader.poll().unwrap().is_ready());
+
+    // Only the writer is notified once the value is released
+    drop(r1);
+    assert!(writer.is_notified());
+    assert!(!reader.is_notified());
+
+    let mut b = ready(writer.poll().unwrap());
+    *b = 2;
+    drop(b);
+
+    assert!(reader.is_notified());
+    assert_eq!(*ready(reader.poll().unwrap()), 2);
+
+    // Dropping a waiting writer lets readers through
+    let r1 = s.try_borrow_shared().unwrap();
+
+    let mut writer = Harness::new(s.borrow());
+    assert!(!writer.poll().unwrap().is_ready());
+
+    let mut other = s.clone();
+    let mut reader = Harness::poll_fn(|| other.poll_borrow_shared());
+    assert!(!reader.poll().unwrap().is_ready());
+
+    drop(writer);
+    assert!(reader.is_notified());
+    assert!(reader.poll().unwrap().is_ready());
+
+    drop(r1);
+}
+
+#[test]
+fn test_writer_priority_upgrade() {
+    let s = Borrow::builder().policy(Policy::WriterPriority).build(1);
+
+    let u = s.try_borrow_upgradeable().unwrap();
+    let r1 = s.try_borrow_shared().unwrap();
+
+    let mut upgrade = Harness::new(UpgradeableGuard::upgrade(u));
+    assert!(!upgrade.poll().unwrap().is_ready());
+
+    // New readers are held back while the upgrade waits
+    assert!(s.try_borrow_shared().is_err());
+
+    let mut other = s.clone();
+    let mut reader = Harness::poll_fn(|| other.poll_borrow_shared());
+    assert!(!reader.poll().unwrap().is_ready());
+
+    drop(r1);
+    assert!(upgrade.is_notified());
+    assert!(!reader.is_notified());
+
+    let mut b = ready(upgrade.poll().unwrap());
+    *b = 2;
+    drop(b);
+
+    assert!(reader.is_notified());
+    assert_eq!(*ready(reader.poll().unwrap()), 2);
+}
+
+#[test]
+fn test_writer_priority_poll_ready() {
+    let s = Borrow::builder().policy(Policy::WriterPriority).build(1);
+    let b = s.try_borrow().unwrap();
+
+    let mut writer = s.clone();
+
+    Harness::poll_fn(|| writer.poll_ready()).with(|harness| {
+        assert!(!harness.poll().unwrap().is_ready());
+
+        drop(b);
+        assert!(harness.poll().unwrap().is_ready());
+    });
+
+    // Acquiring the borrow stops the handle from holding back readers
+    drop(writer.try_borrow().unwrap());
+    assert!(s.try_borrow_shared().is_ok());
+}
+
+#[test]
+fn test_fifo_policy() {
+    let s = Borrow::builder().policy(Policy::Fifo).build(1);
+
+    let b = s.try_borrow().unwrap();
+
+    let mut writer = Harness::new(s.borrow());
+    assert!(!writer.poll().unwrap().is_ready());
+
+    let mut other = s.clone();
+    let mut reader = Harness::poll_fn(|| other.poll_borrow_shared());
+    assert!(!reader.poll().unwrap().is_ready());
+
+    // The writer arrived first and is the only one notified
+    drop(b);
+    assert!(writer.is_notified());
+    assert!(!reader.is_notified());
+
+    // The reader cannot skip ahead of the writer
+    assert!(!reader.poll().unwrap().is_ready());
+    assert!(s.try_borrow_shared().is_err());
+
+    // Formatting the value does not wait for its turn
+    assert_eq!(format!("{:?}", s), "Borrow { data: 1, poisoned: false }");
+
+    let b = ready(writer.poll().unwrap());
+    drop(b);
+
+    assert!(reader.is_notified());
+    assert!(reader.poll().unwrap().is_ready());
+}
+
+#[test]
+fn test_fifo_poll_ready() {
+    let s = Borrow::builder().policy(Policy::Fifo).build(1);
+    let b = s.try_borrow().unwrap();
+
+    let mut writer = s.clone();
+
+    Harness::poll_fn(|| writer.poll_ready()).with(|harness| {
+        assert!(!harness.poll().unwrap().is_ready());
+
+        drop(b);
+        assert!(harness.poll().unwrap().is_ready());
+    });
+
+    // The handle is first in line, the next borrow succeeds
+    drop(writer.try_borrow().unwrap());
+
+    // And the handle no longer holds its place in the queue
+    assert!(s.try_borrow().is_ok());
+}
+
+#[test]
+fn test_fifo_upgrade() {
+    let s = Borrow::builder().policy(Policy::Fifo).build(1);
+
+    let u = s.try_borrow_upgradeable().unwrap();
+    let r1 = s.try_borrow_shared().unwrap();
+
+    let mut writer = Harness::new(s.borrow());
+    assert!(!writer.poll().unwrap().is_ready());
+
+    // The upgrade goes ahead of the writer, which cannot acquire the borrow
+    // before it anyway
+    let mut upgrade = Harness::new(UpgradeableGuard::upgrade(u));
+    assert!(!upgrade.poll().unwrap().is_ready());
+    assert!(s.try_borrow_shared().is_err());
+
+    drop(r1);
+    assert!(upgrade.is_notified());
+    assert!(!writer.is_notified());
+
+    let b = ready(upgrade.poll().unwrap());
+    drop(b);
+
+    assert!(writer.is_notified());
+    assert!(writer.poll().unwrap().is_ready());
+}
+
+#[test]
+fn test_borrow_future() {
+    let s = Borrow::new(vec![1]);
+
+    let b = s.try_borrow().unwrap();
+
+    let mut fut = Harness::new(s.borrow().map(|mut b| {
+        b.push(2);
+    }));
+
+    assert!(!fut.poll().unwrap().is_ready());
+
+    drop(b);
+    assert!(fut.is_notified());
+    assert!(fut.poll().unwrap().is_ready());
+
+    assert_eq!(*s.try_borrow().unwrap(), [1, 2]);
+}
+
+#[test]
+fn test_drop_pending_borrow_future() {
+    let s = Borrow::new_fair(1);
+
+    let b = s.try_borrow().unwrap();
+
+    let mut first = Harness::new(s.borrow());
+    let mut second = Harness::new(s.borrow());
+
+    assert!(!first.poll().unwrap().is_ready());
+    assert!(!second.poll().unwrap().is_ready());
+
+    drop(b);
+    assert!(first.is_notified());
+    assert!(!second.is_notified());
+
+    // Dropping the first future hands its place to the second one
+    drop(first);
+    assert!(second.is_notified());
+    assert_eq!(*ready(second.poll().unwrap()), 1);
+}
+
+#[test]
+fn test_poison_recovery() {
+    // Without the `std` feature, panics are only detected through a hook.
+    set_panicking_hook(std::thread::panicking);
+
+    let mut s = Borrow::new(vec![1]);
+
+    let b = s.try_borrow().unwrap();
+
+    let res = panic::catch_unwind(panic::AssertUnwindSafe(move || {
+        let _b = b;
+        panic!("boom");
+    }));
+
+    assert!(res.is_err());
+    assert!(s.is_poisoned());
+
+    // Borrowing fails and reports why
+    assert!(s.try_borrow().unwrap_err().is_poisoned());
+    assert!(s.try_borrow_shared().unwrap_err().is_poisoned());
+    assert!(Harness::poll_fn(|| s.poll_borrow()).poll().unwrap_err().is_poisoned());
+
+    {
+        // The value can still be accessed
+        let mut b = s.try_borrow_ignore_poison().unwrap();
+        b.push(2);
+
+        // Still exclusive
+        assert!(!s.try_borrow_ignore_poison().unwrap_err().is_poisoned());
+    }
+
+    s.clear_poison();
+    assert!(!s.is_poisoned());
+
+    assert_eq!(*s.try_borrow().unwrap(), [1, 2]);
+}
+
+#[test]
+fn test_poison_notifies_fifo_waiters() {
+    set_panicking_hook(std::thread::panicking);
+
+    let s = Borrow::new_fair(1);
+    let b = s.try_borrow().unwrap();
+
+    let mut first = Harness::new(s.borrow());
+    assert!(!first.poll().unwrap().is_ready());
+
+    let mut second = Harness::new(s.borrow());
+    assert!(!second.poll().unwrap().is_ready());
+
+    let mut other = s.clone();
+    let mut third = Harness::poll_fn(|| other.poll_ready());
+    assert!(!third.poll().unwrap().is_ready());
+
+    let res = panic::catch_unwind(panic::AssertUnwindSafe(move || {
+        let _b = b;
+        panic!("boom");
+    }));
+    assert!(res.is_err());
+
+    // Each waiter passes the poisoning on to the next one
+    assert!(first.is_notified());
+    assert!(!second.is_notified());
+    assert!(first.poll().unwrap_err().is_poisoned());
+
+    assert!(second.is_notified());
+    assert!(second.poll().unwrap_err().is_poisoned());
+
+    assert!(third.is_notified());
+    assert!(third.poll().unwrap_err().is_poisoned());
+}
+
+#[test]
+fn test_clone_borrow() {
+    let mut s1 = Borrow::new(1);
+    let mut s2 = s1.clone();
+
+    let mut b = s1.try_borrow().unwrap();
+
+    // Clones share the borrow state
+    assert!(s2.try_borrow().is_err());
+
+    {
+        let mut t1 = Harness::poll_fn(|| s1.poll_ready());
+        let mut t2 = Harness::poll_fn(|| s2.poll_ready());
+
+        assert!(!t1.poll().unwrap().is_ready());
+        assert!(!t2.poll().unwrap().is_ready());
+
+        *b += 1;
+        drop(b);
+
+        assert!(t1.is_notified());
+        assert!(t2.is_notified());
+    }
+
+    assert_eq!(*s2.try_borrow().unwrap(), 2);
+}
+
+#[test]
+fn test_weak_borrow() {
+    let s = Borrow::new(1);
+    let weak = s.downgrade();
+
+    assert_eq!(*weak.upgrade().unwrap().try_borrow().unwrap(), 1);
+
+    // Outstanding guards keep the value alive
+    let b = s.try_borrow().unwrap();
+    drop(s);
+
+    let s = weak.upgrade().unwrap();
+    assert!(s.try_borrow().is_err());
+
+    drop(b);
+    drop(s);
+
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn test_get_mut_and_into_inner() {
+    let mut s = Borrow::new(vec![1]);
+
+    s.get_mut().unwrap().push(2);
+
+    {
+        // Other handles prevent access
+        let s2 = s.clone();
+        assert!(s.get_mut().is_none());
+
+        let s2 = s2.try_unwrap().unwrap_err();
+        drop(s2);
+    }
+
+    assert_eq!(s.into_inner(), [1, 2]);
+}
+
+#[test]
+fn test_unwrap_waits_for_guards() {
+    let s = Borrow::new(vec![1]);
+
+    let mut b = s.try_borrow().unwrap();
+
+    let s = s.try_unwrap().unwrap_err();
+    let mut unwrap = Harness::new(s.unwrap());
+
+    assert!(!unwrap.poll().unwrap().is_ready());
+
+    b.push(2);
+    drop(b);
+
+    assert!(unwrap.is_notified());
+    assert_eq!(ready(unwrap.poll().unwrap()), [1, 2]);
+}
+
+#[test]
+fn test_unwrap_fair_does_not_block_waiters() {
+    let s = Borrow::new_fair(1);
+    let other = s.clone();
+
+    let b = s.try_borrow().unwrap();
+
+    let mut unwrap = Harness::new(s.unwrap());
+    assert!(!unwrap.poll().unwrap().is_ready());
+
+    drop(b);
+    assert!(unwrap.is_notified());
+
+    // The other handle is still alive, so the value cannot be unwrapped
+    let s = unwrap.poll().unwrap_err();
+
+    assert!(other.try_borrow().is_ok());
+    drop(s);
+}
+
+#[test]
+fn test_unwrap_guard_dropped_on_other_thread() {
+    for i in 0..100 {
+        let s = Borrow::new(i);
+        let b = s.try_borrow().unwrap();
+
+        let th = std::thread::spawn(move || drop(b));
+
+        // The guard is not another handle, even while it is being dropped
+        assert_eq!(s.unwrap().wait().unwrap(), i);
+        th.join().unwrap();
+    }
+}
+
+#[test]
+fn test_borrow_map_split() {
+    let mut s = Borrow::new((vec![1], "hello".to_string()));
+
+    let b = s.try_borrow().unwrap();
+    let (mut a, mut b) = Borrow::map_split(b, |v| (&mut v.0, &mut v.1));
+
+    a.push(2);
+    b.push_str("-world");
+
+    let mut ready = Harness::poll_fn(|| s.poll_ready());
+    assert!(!ready.poll().unwrap().is_ready());
+
+    // The borrow is held until both halves are dropped
+    drop(a);
+    assert!(!ready.is_notified());
+    assert!(!ready.poll().unwrap().is_ready());
+
+    drop(b);
+    assert!(ready.is_notified());
+    assert!(ready.poll().unwrap().is_ready());
+    drop(ready);
+
+    let b = s.try_borrow().unwrap();
+    assert_eq!(b.0, [1, 2]);
+    assert_eq!(b.1, "hello-world");
+}
+
+#[test]
+fn test_unsized_borrow() {
+    let s = Borrow::new(vec![1, 2, 3]);
+
+    // Map to a slice
+    let b = Borrow::map(s.try_borrow().unwrap(), |v| &mut v[1..]);
+    assert_eq!(&*b, [2, 3]);
+    assert!(s.try_borrow().is_err());
+    drop(b);
+
+    // Borrow a trait object, sharing state with the sized handle
+    let other = s.clone().unsize::<dyn AsRef<[i32]>, _>(|b| b);
+    let b = other.try_borrow().unwrap();
+    assert_eq!(b.as_ref(), [1, 2, 3]);
+    assert!(s.try_borrow().is_err());
+    drop(b);
+
+    drop(other);
+    assert_eq!(s.into_inner(), [1, 2, 3]);
+}
+
+#[test]
+fn test_non_static_borrow() {
+    let mut data = vec![1];
+
+    {
+        let s = Borrow::new(&mut data);
+        s.try_borrow().unwrap().push(2);
+
+        let mut borrow = Harness::new(s.borrow());
+        let mut b = ready(borrow.poll().unwrap());
+        b.push(3);
+    }
+
+    assert_eq!(data, [1, 2, 3]);
+}
+
+#[test]
+fn test_downgrade_guard() {
+    let mut s1 = Borrow::new(vec![1]);
+    let mut s2 = s1.clone();
+
+    let mut b = s1.try_borrow().unwrap();
+    b.push(2);
+
+    let mut exclusive = Harness::poll_fn(|| s1.poll_borrow().map(|r| r.map(|_| ())));
+    assert!(!exclusive.poll().unwrap().is_ready());
+
+    let mut shared = Harness::poll_fn(|| s2.poll_borrow_shared());
+    assert!(!shared.poll().unwrap().is_ready());
+
+    let b = BorrowGuard::downgrade(b);
+    assert_eq!(*b, [1, 2]);
+
+    // Shared waiters are let through, writers are not
+    assert!(shared.is_notified());
+    let other = ready(shared.poll().unwrap());
+    assert_eq!(*other, [1, 2]);
+    drop(shared);
+
+    assert!(!exclusive.poll().unwrap().is_ready());
+
+    drop(b);
+    drop(other);
+    assert!(exclusive.is_notified());
+    assert!(exclusive.poll().unwrap().is_ready());
+}
+
+#[test]
+fn test_upgradeable_borrow() {
+    let mut s = Borrow::new(vec![1]);
+
+    let b = s.try_borrow_upgradeable().unwrap();
+    let other = s.try_borrow_shared().unwrap();
+
+    // Only a single upgradeable borrow at a time and no writers
+    assert!(s.try_borrow_upgradeable().is_err());
+    assert!(s.try_borrow().is_err());
+
+    let mut upgradeable = Harness::poll_fn(|| s.poll_borrow_upgradeable());
+    assert!(!upgradeable.poll().unwrap().is_ready());
+
+    let mut upgrade = Harness::new(UpgradeableGuard::upgrade(b));
+    assert!(!upgrade.poll().unwrap().is_ready());
+
+    // Releasing the shared borrow lets the upgrade through
+    assert_eq!(*other, [1]);
+    drop(other);
+    assert!(upgrade.is_notified());
+
+    let mut b = ready(upgrade.poll().unwrap());
+    b.push(2);
+
+    assert!(!upgradeable.poll().unwrap().is_ready());
+    drop(b);
+
+    assert!(upgradeable.is_notified());
+    let b = ready(upgradeable.poll().unwrap());
+    assert_eq!(*b, [1, 2]);
+    drop(upgradeable);
+
+    // Dropping a pending upgrade releases the borrow
+    let other = s.try_borrow_shared().unwrap();
+    let mut upgrade = Harness::new(UpgradeableGuard::upgrade(b));
+    assert!(!upgrade.poll().unwrap().is_ready());
+    drop(upgrade);
+
+    let b = s.try_borrow_upgradeable().unwrap();
+    let b = UpgradeableGuard::downgrade(b);
+    assert!(s.try_borrow_upgradeable().is_ok());
+
+    drop((b, other));
+    assert!(s.try_borrow().is_ok());
+}
+
+#[test]
+fn test_borrow_timeout() {
+    let clock = MockClock::new();
+    let s = Borrow::new_fair(1);
+
+    let b = s.try_borrow().unwrap();
+
+    let mut borrow = Harness::new(s.borrow().timeout(clock.delay(Duration::from_secs(5))));
+    assert!(!borrow.poll().unwrap().is_ready());
+
+    clock.advance(Duration::from_secs(3));
+    assert!(borrow.is_notified());
+    assert!(!borrow.poll().unwrap().is_ready());
+
+    clock.advance(Duration::from_secs(2));
+    let err = borrow.poll().unwrap_err();
+    assert!(err.is_timeout());
+    assert!(!err.is_poisoned());
+
+    // The expired future gives up its place in the queue
+    drop(b);
+    assert!(s.try_borrow().is_ok());
+    drop(borrow);
+
+    // The borrow wins when acquired before the deadline
+    let mut borrow = Harness::new(s.borrow().timeout(clock.delay(Duration::from_secs(5))));
+    assert!(borrow.poll().unwrap().is_ready());
+}
+
+#[test]
+fn test_borrow_cancel() {
+    let s = Borrow::new(1);
+    let _b = s.try_borrow().unwrap();
+
+    let (tx, rx) = oneshot::channel::<()>();
+
+    let mut borrow = Harness::new(s.borrow().cancel_on(rx));
+    assert!(!borrow.poll().unwrap().is_ready());
+
+    tx.send(()).unwrap();
+    assert!(borrow.is_notified());
+    assert!(borrow.poll().unwrap_err().is_canceled());
+}
+
+#[test]
+fn test_borrow_when() {
+    let s = Borrow::new(0);
+
+    let mut borrow = Harness::new(s.borrow_when(|v| *v >= 2));
+    assert!(!borrow.poll().unwrap().is_ready());
+
+    // The borrow is not held while waiting
+    let b = s.try_borrow().unwrap();
+
+    // Releasing without mutating the value does not notify the task
+    drop(b);
+    assert!(!borrow.is_notified());
+
+    let mut b = s.try_borrow().unwrap();
+    *b = 1;
+    drop(b);
+    assert!(borrow.is_notified());
+    assert!(!borrow.poll().unwrap().is_ready());
+
+    let mut b = s.try_borrow().unwrap();
+    *b = 2;
+
+    // The value cannot be borrowed until the guard is dropped
+    let mut other = s.clone();
+    let mut other = Harness::poll_fn(|| other.poll_borrow_when(|v| *v == 2));
+    assert!(!other.poll().unwrap().is_ready());
+
+    drop(b);
+    let b = ready(borrow.poll().unwrap());
+    assert_eq!(*b, 2);
+
+    drop(b);
+    assert!(other.is_notified());
+    assert_eq!(*ready(other.poll().unwrap()), 2);
+}
+
+#[test]
+fn test_poll_changed() {
+    let s = Borrow::new(0);
+    let version = s.version();
+
+    let mut observer = s.clone();
+    let mut changed = Harness::poll_fn(|| Ok::<_, ()>(observer.poll_changed(version)));
+    assert!(!changed.poll().unwrap().is_ready());
+
+    // Observing does not take the borrow
+    let b = s.try_borrow().unwrap();
+    assert_eq!(*b, 0);
+    drop(b);
+    assert!(!changed.is_notified());
+    assert_eq!(s.version(), version);
+
+    let mut b = s.try_borrow().unwrap();
+    *b = 1;
+    drop(b);
+    assert!(changed.is_notified());
+
+    let new_version = ready(changed.poll().unwrap());
+    assert_ne!(new_version, version);
+    assert_eq!(s.version(), new_version);
+
+    // Downgrading a mutated borrow also bumps the version
+    let mut b = s.try_borrow().unwrap();
+    *b = 2;
+    let b = BorrowGuard::downgrade(b);
+    assert_ne!(s.version(), new_version);
+    drop(b);
+}
+
+#[test]
+fn test_map_version() {
+    let s = Borrow::new((0, 0));
+    let version = s.version();
+
+    // Projecting only reads the value
+    let b = Borrow::map(s.try_borrow().unwrap(), |v| &mut v.0);
+    assert_eq!(*b, 0);
+    drop(b);
+
+    let (b1, b2) = Borrow::map_split(s.try_borrow().unwrap(), |v| (&mut v.0, &mut v.1));
+    drop((b1, b2));
+    assert_eq!(s.version(), version);
+
+    // Writing through the projected guard bumps the version
+    let mut b = Borrow::map(s.try_borrow().unwrap(), |v| &mut v.1);
+    *b = 1;
+    drop(b);
+    assert_ne!(s.version(), version);
+}
+
+#[test]
+fn test_guard_stream_sink() {
+    let sink = Borrow::new(vec![]);
+    let mut guard = sink.try_borrow().unwrap();
+    assert!(guard.start_send(1).unwrap().is_ready());
+    assert!(guard.poll_complete().unwrap().is_ready());
+    drop(guard);
+
+    let stream = Borrow::new(stream::iter_ok::<_, ()>(vec![1, 2]));
+    let values = stream.try_borrow().unwrap().collect().wait().unwrap();
+    assert_eq!(values, vec![1, 2]);
+
+    // The stream is left exhausted
+    assert_eq!(stream.try_borrow().unwrap().poll(), Ok(Async::Ready(None)));
+
+    let sink = Borrow::new(vec![1]);
+    let b = sink.try_borrow().unwrap();
+
+    let mut send = Harness::new(sink.with_borrow(|guard| {
+        guard.send(2).map(drop).map_err(|()| -> BorrowError { unreachable!() })
+    }));
+    assert!(!send.poll().unwrap().is_ready());
+    drop(b);
+
+    assert!(send.is_notified());
+    assert!(send.poll().unwrap().is_ready());
+    assert_eq!(*sink.try_borrow().unwrap(), vec![1, 2]);
+
+    // The borrow is held for the lifetime of the future
+    let mut send = Harness::new(sink.with_borrow(|guard| {
+        future::empty::<(), BorrowError>().map(move |_| drop(guard))
+    }));
+    assert!(!send.poll().unwrap().is_ready());
+    assert!(sink.try_borrow().is_err());
+
+    drop(send);
+    assert!(sink.try_borrow().is_ok());
+}
+
+#[cfg(feature = "diagnostics")]
+#[test]
+fn test_holder_diagnostics() {
+    let s = Borrow::new(1);
+    assert!(s.holders().is_empty());
+
+    let line = line!() + 1;
+    let b = s.try_borrow().unwrap();
+
+    let holders = s.holders();
+    assert_eq!(holders.len(), 1);
+    assert!(holders[0].is_exclusive());
+    assert_eq!(holders[0].location().file(), file!());
+    assert_eq!(holders[0].location().line(), line);
+    assert_eq!(holders[0].thread_id(), std::thread::current().id());
+
+    assert!(format!("{:?}", s).contains(&format!("{}:{}", file!(), line)));
+
+    assert_eq!(s.long_held(Duration::from_secs(0)).len(), 1);
+    assert!(s.long_held(Duration::from_secs(3600)).is_empty());
+
+    let b = BorrowGuard::downgrade(b);
+    let other = s.try_borrow_shared().unwrap();
+
+    let holders = s.holders();
+    assert_eq!(holders.len(), 2);
+    assert!(holders.iter().all(|holder| holder.is_shared()));
+
+    drop((b, other));
+    assert!(s.holders().is_empty());
+}
+
+#[cfg(feature = "stats")]
+#[test]
+fn test_borrow_stats() {
+    let mut s = Borrow::new(1);
+    let other = s.clone();
+
+    let b = other.try_borrow().unwrap();
+    assert!(s.try_borrow().is_err());
+    assert!(s.try_borrow_shared().is_err());
+
+    {
+        let mut borrow = Harness::poll_fn(|| s.poll_borrow().map(|r| r.map(|_| ())));
+        assert!(!borrow.poll().unwrap().is_ready());
+
+        std::thread::sleep(Duration::from_millis(10));
+        drop(b);
+
+        assert!(borrow.poll().unwrap().is_ready());
+    }
+
+    // Formatting the value is not recorded
+    let _ = format!("{:?}", s);
+
+    let stats = s.stats();
+    assert_eq!(stats.borrows(), 2);
+    assert_eq!(stats.failed_try_borrows(), 2);
+    assert_eq!(stats.not_ready(), 1);
+    assert!(stats.hold_time() >= Duration::from_millis(10));
+    assert!(stats.max_wait() >= Duration::from_millis(10));
+}
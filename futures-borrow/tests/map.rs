@@ -0,0 +1,82 @@
+#![cfg(feature = "std")]
+
+extern crate futures;
+extern crate futures_borrow;
+extern crate futures_test;
+
+use futures::Async;
+use futures_borrow::*;
+use futures_test::Harness;
+
+fn ready<T>(res: Async<T>) -> T {
+    match res {
+        Async::Ready(v) => v,
+        Async::NotReady => panic!("not ready"),
+    }
+}
+
+#[test]
+fn test_borrow_per_key() {
+    let map: BorrowMap<&str, Vec<i32>> = BorrowMap::new();
+    assert!(map.is_empty());
+
+    let mut a = map.try_borrow("a").unwrap();
+    a.push(1);
+
+    // Other keys are independent
+    let b = map.try_borrow("b").unwrap();
+    assert!(b.is_empty());
+    assert_eq!(map.len(), 2);
+
+    assert!(map.try_borrow("a").is_err());
+
+    // Entries are evicted once released
+    drop(b);
+    assert!(!map.contains_key(&"b"));
+    assert_eq!(map.len(), 1);
+
+    drop(a);
+    assert!(map.is_empty());
+    assert!(map.try_borrow("a").unwrap().is_empty());
+}
+
+#[test]
+fn test_borrow_future_keeps_entry() {
+    let map: BorrowMap<u32, Vec<i32>> = BorrowMap::new();
+
+    let mut a = map.try_borrow(1).unwrap();
+
+    let mut borrow = Harness::new(map.borrow(1));
+    assert!(!borrow.poll().unwrap().is_ready());
+
+    a.push(1);
+    drop(a);
+
+    // The pending future keeps the entry, and the value, alive
+    assert!(map.contains_key(&1));
+    assert!(borrow.is_notified());
+
+    let a = ready(borrow.poll().unwrap());
+    assert_eq!(*a, [1]);
+
+    drop(borrow);
+    assert!(map.contains_key(&1));
+
+    drop(a);
+    assert!(map.is_empty());
+
+    // Dropping a future without borrowing evicts the entry
+    drop(map.borrow(2));
+    assert!(map.is_empty());
+}
+
+#[test]
+fn test_guard_outlives_map() {
+    let map: BorrowMap<u32, String> = BorrowMap::new();
+
+    let mut a = map.try_borrow(1).unwrap();
+    drop(map);
+
+    a.push_str("hello");
+    assert_eq!(*a, "hello");
+}
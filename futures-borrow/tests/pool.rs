@@ -0,0 +1,103 @@
+extern crate futures;
+extern crate futures_borrow;
+extern crate futures_test;
+
+use futures::Async;
+use futures_borrow::*;
+use futures_test::Harness;
+
+use std::panic;
+
+fn ready<T>(res: Async<T>) -> T {
+    match res {
+        Async::Ready(v) => v,
+        Async::NotReady => panic!("not ready"),
+    }
+}
+
+#[test]
+fn test_acquire_any_item() {
+    let mut pool: BorrowPool<_> = vec![1, 2].into_iter().collect();
+    assert_eq!(pool.len(), 2);
+
+    let a = pool.try_acquire().unwrap();
+    let b = pool.try_acquire().unwrap();
+    assert_eq!(*a + *b, 3);
+    assert!(pool.try_acquire().is_none());
+
+    let mut acquire = Harness::poll_fn(|| Ok::<_, ()>(pool.poll_acquire()));
+    assert!(!acquire.poll().unwrap().is_ready());
+
+    drop(b);
+    assert!(acquire.is_notified());
+
+    let mut b = ready(acquire.poll().unwrap());
+    *b += 10;
+    drop((a, b));
+    drop(acquire);
+
+    let mut values = vec![pool.try_remove().unwrap(), pool.try_remove().unwrap()];
+    values.sort();
+    assert_eq!(values, [1, 12]);
+    assert!(pool.is_empty());
+}
+
+#[test]
+fn test_acquire_future() {
+    let pool = BorrowPool::new();
+
+    let mut acquire = Harness::new(pool.acquire());
+    assert!(!acquire.poll().unwrap().is_ready());
+
+    // Growing the pool notifies waiters
+    pool.push("hello");
+    assert!(acquire.is_notified());
+
+    let guard = ready(acquire.poll().unwrap());
+    assert_eq!(*guard, "hello");
+
+    let mut other = Harness::new(pool.clone().acquire());
+    assert!(!other.poll().unwrap().is_ready());
+
+    drop(guard);
+    assert!(other.is_notified());
+    assert!(other.poll().unwrap().is_ready());
+}
+
+#[test]
+fn test_shrink_pool() {
+    let pool: BorrowPool<_> = vec![1].into_iter().collect();
+
+    let guard = pool.try_acquire().unwrap();
+    assert!(pool.try_remove().is_none());
+
+    let mut remove = Harness::new(pool.remove());
+    assert!(!remove.poll().unwrap().is_ready());
+
+    drop(guard);
+    assert!(remove.is_notified());
+    assert_eq!(ready(remove.poll().unwrap()), 1);
+    assert!(pool.is_empty());
+    assert!(pool.try_acquire().is_none());
+}
+
+#[test]
+fn test_evict_poisoned_item() {
+    // Without the `std` feature, panics are only detected through a hook.
+    set_panicking_hook(std::thread::panicking);
+
+    let pool: BorrowPool<_> = vec![1, 2].into_iter().collect();
+
+    let guard = pool.try_acquire().unwrap();
+    let value = *guard;
+
+    let res = panic::catch_unwind(panic::AssertUnwindSafe(move || {
+        let _guard = guard;
+        panic!();
+    }));
+    assert!(res.is_err());
+
+    let guard = pool.try_acquire().unwrap();
+    assert!(*guard != value);
+    assert_eq!(pool.len(), 1);
+}
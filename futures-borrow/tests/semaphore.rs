@@ -0,0 +1,155 @@
+extern crate futures;
+extern crate futures_borrow;
+extern crate futures_test;
+
+use futures::Async;
+use futures_borrow::*;
+use futures_test::Harness;
+
+use std::panic;
+
+fn ready<T>(res: Async<T>) -> T {
+    match res {
+        Async::Ready(v) => v,
+        Async::NotReady => panic!("not ready"),
+    }
+}
+
+#[test]
+fn test_weighted_acquire() {
+    let mut sem = Semaphore::new(5);
+
+    let a = sem.try_acquire(2).unwrap();
+    let b = sem.try_acquire(3).unwrap();
+    assert_eq!(a.permits() + b.permits(), 5);
+    assert_eq!(sem.available_permits(), 0);
+    assert!(!sem.try_acquire(1).unwrap_err().is_poisoned());
+
+    let mut acquire = Harness::poll_fn(|| sem.poll_acquire(3));
+    assert!(!acquire.poll().unwrap().is_ready());
+
+    // Not enough permits are released
+    drop(a);
+    assert!(acquire.is_notified());
+    assert!(!acquire.poll().unwrap().is_ready());
+
+    drop(b);
+    let permit = ready(acquire.poll().unwrap());
+    assert_eq!(permit.permits(), 3);
+    drop(acquire);
+
+    assert_eq!(sem.available_permits(), 2);
+    drop(permit);
+    assert_eq!(sem.available_permits(), 5);
+}
+
+#[test]
+fn test_acquire_in_order() {
+    let sem = Semaphore::new(2);
+    let permit = sem.try_acquire(2).unwrap();
+
+    let mut large = Harness::new(sem.acquire(2));
+    assert!(!large.poll().unwrap().is_ready());
+
+    let mut small = Harness::new(sem.acquire(1));
+    assert!(!small.poll().unwrap().is_ready());
+
+    // The small request does not skip ahead of the large one
+    drop(permit);
+    assert!(large.is_notified());
+    assert!(!small.is_notified());
+    assert!(!small.poll().unwrap().is_ready());
+    assert!(sem.try_acquire(1).is_err());
+
+    let permit = ready(large.poll().unwrap());
+    drop(permit);
+
+    assert!(small.is_notified());
+    assert!(small.poll().unwrap().is_ready());
+}
+
+#[test]
+fn test_add_permits() {
+    let sem = Semaphore::new(0);
+
+    let mut first = Harness::new(sem.acquire(1));
+    assert!(!first.poll().unwrap().is_ready());
+
+    let mut second = Harness::new(sem.acquire(1));
+    assert!(!second.poll().unwrap().is_ready());
+
+    sem.add_permits(2);
+    assert!(first.is_notified());
+    let p1 = ready(first.poll().unwrap());
+
+    // The remaining permit is passed on to the next waiter
+    assert!(second.is_notified());
+    let p2 = ready(second.poll().unwrap());
+    assert_eq!(sem.available_permits(), 0);
+    drop((p1, p2));
+    assert_eq!(sem.available_permits(), 2);
+
+    // Dropping a pending acquisition lets the next waiter through
+    let _permit = sem.try_acquire(2).unwrap();
+
+    let mut first = Harness::new(sem.acquire(1));
+    assert!(!first.poll().unwrap().is_ready());
+
+    let mut second = Harness::new(sem.acquire(1));
+    assert!(!second.poll().unwrap().is_ready());
+
+    sem.add_permits(1);
+    drop(first);
+    assert!(second.is_notified());
+    assert!(second.poll().unwrap().is_ready());
+}
+
+#[test]
+fn test_acquire_more_than_total() {
+    let sem = Semaphore::new(2);
+
+    let mut large = Harness::new(sem.acquire(3));
+    assert!(!large.poll().unwrap().is_ready());
+
+    // The request can never be satisfied with the current permits, and the
+    // tasks after it wait behind it
+    let mut small = Harness::new(sem.acquire(1));
+    assert!(!small.poll().unwrap().is_ready());
+    assert!(sem.try_acquire(1).is_err());
+
+    // Until enough permits are added
+    sem.add_permits(1);
+    assert!(large.is_notified());
+    let permit = ready(large.poll().unwrap());
+    assert_eq!(permit.permits(), 3);
+
+    drop(permit);
+    assert!(small.is_notified());
+    assert!(small.poll().unwrap().is_ready());
+}
+
+#[test]
+fn test_poisoned_semaphore() {
+    // Without the `std` feature, panics are only detected through a hook.
+    set_panicking_hook(std::thread::panicking);
+
+    let sem = Semaphore::new(1);
+    let permit = sem.try_acquire(1).unwrap();
+
+    let mut acquire = Harness::new(sem.acquire(1));
+    assert!(!acquire.poll().unwrap().is_ready());
+
+    let res = panic::catch_unwind(panic::AssertUnwindSafe(move || {
+        let _permit = permit;
+        panic!();
+    }));
+    assert!(res.is_err());
+
+    assert!(sem.is_poisoned());
+    assert!(acquire.is_notified());
+    assert!(acquire.poll().unwrap_err().is_poisoned());
+    assert!(sem.try_acquire(1).unwrap_err().is_poisoned());
+
+    sem.clear_poison();
+    assert!(sem.try_acquire(1).is_ok());
+}
@@ -0,0 +1,108 @@
+use futures::{Future, Poll, Async};
+use futures::task::{self, Task};
+
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+
+/// A manually driven time source.
+///
+/// Time only moves forward when `advance` is called, which allows testing
+/// timeouts deterministically and without sleeping. `MockClock` is a handle:
+/// cloning it returns a new handle to the same clock.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// Future that completes once the `MockClock` reaches a deadline.
+///
+/// Returned by `MockClock::delay` and `MockClock::delay_until`.
+#[derive(Debug)]
+pub struct Delay {
+    clock: MockClock,
+    deadline: Instant,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// The current time
+    now: Instant,
+
+    /// Tasks waiting on a delay
+    tasks: Vec<Task>,
+}
+
+impl MockClock {
+    /// Create a new clock, starting at the current time.
+    pub fn new() -> MockClock {
+        MockClock {
+            inner: Arc::new(Mutex::new(Inner {
+                now: Instant::now(),
+                tasks: vec![],
+            })),
+        }
+    }
+
+    /// Returns the current time of the clock.
+    pub fn now(&self) -> Instant {
+        self.inner.lock().unwrap().now
+    }
+
+    /// Move the clock forward by `dur`, notifying tasks waiting on a delay.
+    pub fn advance(&self, dur: Duration) {
+        let tasks = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.now += dur;
+            inner.tasks.split_off(0)
+        };
+
+        for task in tasks {
+            task.notify();
+        }
+    }
+
+    /// Returns a future that completes once the clock has advanced by `dur`.
+    pub fn delay(&self, dur: Duration) -> Delay {
+        self.delay_until(self.now() + dur)
+    }
+
+    /// Returns a future that completes once the clock reaches `deadline`.
+    pub fn delay_until(&self, deadline: Instant) -> Delay {
+        Delay {
+            clock: self.clone(),
+            deadline,
+        }
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Delay {
+    /// Returns the instant at which the delay completes.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+impl Future for Delay {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let mut inner = self.clock.inner.lock().unwrap();
+
+        if inner.now >= self.deadline {
+            return Ok(Async::Ready(()));
+        }
+
+        if !inner.tasks.iter().any(|task| task.will_notify_current()) {
+            inner.tasks.push(task::current());
+        }
+
+        Ok(Async::NotReady)
+    }
+}
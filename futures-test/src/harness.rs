@@ -181,6 +181,13 @@ impl ThreadNotify {
         self.state.store(IDLE, Ordering::SeqCst);
     }
 
+    /// Transitions the state from `current` to `new`, returning the previous
+    /// state.
+    fn compare_and_swap(&self, current: usize, new: usize) -> usize {
+        self.state.compare_exchange(current, new, Ordering::SeqCst, Ordering::SeqCst)
+            .unwrap_or_else(|actual| actual)
+    }
+
     fn is_notified(&self) -> bool {
         match self.state.load(Ordering::SeqCst) {
             IDLE => false,
@@ -196,7 +203,7 @@ impl ThreadNotify {
     fn park_timeout(&self, dur: Option<Duration>) {
         // If currently notified, then we skip sleeping. This is checked outside
         // of the lock to avoid acquiring a mutex if not necessary.
-        match self.state.compare_and_swap(NOTIFY, IDLE, Ordering::SeqCst) {
+        match self.compare_and_swap(NOTIFY, IDLE) {
             NOTIFY => return,
             IDLE => {},
             _ => unreachable!(),
@@ -207,7 +214,7 @@ impl ThreadNotify {
         let mut m = self.mutex.lock().unwrap();
 
         // Transition to sleeping
-        match self.state.compare_and_swap(IDLE, SLEEP, Ordering::SeqCst) {
+        match self.compare_and_swap(IDLE, SLEEP) {
             NOTIFY => {
                 // Notified before we could sleep, consume the notification and
                 // exit
@@ -240,7 +247,7 @@ impl ThreadNotify {
             };
 
             // Transition back to idle, loop otherwise
-            if NOTIFY == self.state.compare_and_swap(NOTIFY, IDLE, Ordering::SeqCst) {
+            if NOTIFY == self.compare_and_swap(NOTIFY, IDLE) {
                 return;
             }
         }
@@ -251,7 +258,7 @@ impl Notify for ThreadNotify {
     fn notify(&self, _unpark_id: usize) {
         // First, try transitioning from IDLE -> NOTIFY, this does not require a
         // lock.
-        match self.state.compare_and_swap(IDLE, NOTIFY, Ordering::SeqCst) {
+        match self.compare_and_swap(IDLE, NOTIFY) {
             IDLE | NOTIFY => return,
             SLEEP => {}
             _ => unreachable!(),
@@ -261,7 +268,7 @@ impl Notify for ThreadNotify {
         let _m = self.mutex.lock().unwrap();
 
         // Transition from SLEEP -> NOTIFY
-        match self.state.compare_and_swap(SLEEP, NOTIFY, Ordering::SeqCst) {
+        match self.compare_and_swap(SLEEP, NOTIFY) {
             SLEEP => {}
             _ => return,
         }
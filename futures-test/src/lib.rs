@@ -1,5 +1,7 @@
 extern crate futures;
 
+pub mod clock;
 pub mod harness;
 
+pub use clock::MockClock;
 pub use harness::Harness;
@@ -0,0 +1,122 @@
+use {Shared, Store, Value, Watch, Watchers, WatchInner};
+use snapshot::{Snapshot, Vtable};
+
+use fnv::FnvHashMap;
+use futures::task::AtomicTask;
+
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::AtomicUsize;
+
+/// Configures and creates a watch cell.
+///
+/// Returned by [`Watch::builder`].
+///
+/// [`Watch::builder`]: struct.Watch.html#method.builder
+pub struct Builder<T> {
+    dedup: Option<fn(&T, &T) -> bool>,
+    keep_alive: bool,
+    snapshot: Option<Vtable<T>>,
+}
+
+impl<T> Builder<T> {
+    pub(crate) fn new() -> Builder<T> {
+        Builder {
+            dedup: None,
+            keep_alive: false,
+            snapshot: None,
+        }
+    }
+
+    /// Only notify watchers when the stored value changes.
+    ///
+    /// See [`Watch::new_dedup`] for more details.
+    ///
+    /// [`Watch::new_dedup`]: struct.Watch.html#method.new_dedup
+    pub fn dedup(mut self) -> Builder<T>
+    where T: PartialEq,
+    {
+        self.dedup = Some(T::eq);
+        self
+    }
+
+    /// Set whether the cell stays alive once all watchers have been dropped.
+    ///
+    /// By default, the cell is canceled once all watchers have been dropped:
+    /// [`Store::poll_cancel`] returns `Ready` and storing values fails. When
+    /// `keep_alive` is set, the `Store` keeps the cell alive instead, so that
+    /// new watchers can still be created with [`Store::subscribe`].
+    ///
+    /// [`Store::poll_cancel`]: struct.Store.html#method.poll_cancel
+    /// [`Store::subscribe`]: struct.Store.html#method.subscribe
+    pub fn keep_alive(mut self, keep_alive: bool) -> Builder<T> {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Create the watch cell, returning the consumer / producer halves.
+    pub fn build(self, init: T) -> (Watch<T>, Store<T>) {
+        let inner = Arc::new(WatchInner::new());
+
+        // Insert the watcher
+        let mut watchers = FnvHashMap::with_capacity_and_hasher(0, Default::default());
+        watchers.insert(0, inner.clone());
+
+        let shared = Arc::new(Shared {
+            value: match self.snapshot {
+                Some(vtable) => Value::Snapshot(Snapshot::new(init, vtable)),
+                None => Value::Locked(RwLock::new(init)),
+            },
+            version: AtomicUsize::new(0),
+            watchers: Mutex::new(Watchers {
+                next_id: 1,
+                watchers,
+            }),
+            cancel: AtomicTask::new(),
+        });
+
+        let store = Store {
+            shared: Arc::downgrade(&shared),
+            _keep_alive: if self.keep_alive { Some(shared.clone()) } else { None },
+            dedup: self.dedup,
+        };
+
+        let watch = Watch {
+            shared,
+            inner,
+            id: 0,
+            ver: 0,
+        };
+
+        (watch, store)
+    }
+}
+
+impl<T> Builder<Arc<T>> {
+    /// Store the value as atomically swapped snapshots.
+    ///
+    /// By default, the value is guarded by a read-write lock, so storing a
+    /// value blocks while a watcher holds a [`Ref`]. With snapshots, watchers
+    /// hold their own reference to the value instead, obtained with
+    /// [`Watch::load`] or [`Watch::borrow`], and the producer never blocks on
+    /// them. A stored value is dropped once the cell and all watchers are done
+    /// with it.
+    ///
+    /// [`Ref`]: struct.Ref.html
+    /// [`Watch::load`]: struct.Watch.html#method.load
+    /// [`Watch::borrow`]: struct.Watch.html#method.borrow
+    pub fn snapshot(mut self) -> Builder<Arc<T>> {
+        self.snapshot = Some(Vtable::arc());
+        self
+    }
+}
+
+impl<T> fmt::Debug for Builder<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Builder")
+            .field("dedup", &self.dedup.is_some())
+            .field("keep_alive", &self.keep_alive)
+            .field("snapshot", &self.snapshot.is_some())
+            .finish()
+    }
+}
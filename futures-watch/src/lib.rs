@@ -63,6 +63,15 @@
 //! assert_eq!(*watch.borrow(), "three");
 //! ```
 //!
+//! To receive the values themselves rather than change notifications, a
+//! [`Watch`] can be converted into a stream of values with [`Watch::values`],
+//! or of a function of each new value with [`Watch::map`].
+//!
+//! By default, watchers are notified every time a value is stored. A cell
+//! created with [`Watch::new_dedup`] only notifies watchers when the stored
+//! value differs from the current one, and [`Store::store_if_changed`] does
+//! the same for a single value.
+//!
 //! # Cancellation
 //!
 //! [`Store::poll_cancel`] allows the producer to detect when all [`Watch`]
@@ -72,6 +81,35 @@
 //! When the [`Store`] is dropped, the watch handles will be notified and
 //! [`Watch::is_final`] will return true.
 //!
+//! New watchers can be created from the producer side with
+//! [`Store::subscribe`], as long as the cell has not been canceled. A cell
+//! built with [`Builder::keep_alive`] is never canceled: it stays alive, and
+//! can be subscribed to again, after all watchers have been dropped.
+//!
+//! # Snapshots
+//!
+//! By default, [`Watch::borrow`] holds a read lock on the value, which blocks
+//! the [`Store`] until the borrow is released. A cell holding an `Arc` can be
+//! built with [`Builder::snapshot`] instead: the value is then atomically
+//! swapped on every store, and [`Watch::load`] returns an owned snapshot of it.
+//! Watchers never block the producer, no matter how long they hold on to a
+//! snapshot.
+//!
+//! ```
+//! # use futures_watch::*;
+//! use std::sync::Arc;
+//!
+//! let (watch, mut store) = Watch::builder()
+//!     .snapshot()
+//!     .build(Arc::new("hello"));
+//!
+//! let value = watch.borrow();
+//! store.store(Arc::new("goodbye")).unwrap();
+//!
+//! assert_eq!(**value, "hello");
+//! assert_eq!(*watch.load(), "goodbye");
+//! ```
+//!
 //! # Thread safety
 //!
 //! Both [`Watch`] and [`Store`] are thread safe. They can be moved to other
@@ -82,7 +120,15 @@
 //! [`Store`]: struct.Store.html
 //! [`Watch::new`]: struct.Watch.html#method.new
 //! [`Watch::borrow`]: struct.Watch.html#method.borrow
+//! [`Watch::values`]: struct.Watch.html#method.values
+//! [`Watch::map`]: struct.Watch.html#method.map
+//! [`Watch::load`]: struct.Watch.html#method.load
 //! [`Watch::is_final`]: struct.Watch.html#method.is_final
+//! [`Watch::new_dedup`]: struct.Watch.html#method.new_dedup
+//! [`Store::store_if_changed`]: struct.Store.html#method.store_if_changed
+//! [`Store::subscribe`]: struct.Store.html#method.subscribe
+//! [`Builder::keep_alive`]: struct.Builder.html#method.keep_alive
+//! [`Builder::snapshot`]: struct.Builder.html#method.snapshot
 //! [`Store::poll_cancel`]: struct.Store.html#method.poll_cancel
 
 #![deny(warnings, missing_docs, missing_debug_implementations)]
@@ -90,6 +136,8 @@
 extern crate fnv;
 extern crate futures;
 
+use snapshot::Snapshot;
+
 use fnv::FnvHashMap;
 use futures::{Stream, Sink, Poll, Async, AsyncSink, StartSend};
 use futures::task::AtomicTask;
@@ -99,9 +147,15 @@ use std::sync::{Arc, Weak, Mutex, RwLock, RwLockReadGuard};
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::SeqCst;
 
+mod builder;
+mod map;
+mod snapshot;
+
 /// Uses a `Watch` to produce a `Stream` of mapped values.
 pub mod then_stream;
 
+pub use builder::Builder;
+pub use map::{Map, Values};
 pub use then_stream::Then;
 
 /// A future-aware cell that receives notifications when the inner value is
@@ -143,6 +197,14 @@ pub struct Watch<T> {
 #[derive(Debug)]
 pub struct Store<T> {
     shared: Weak<Shared<T>>,
+
+    /// Keeps the cell alive once all watchers have been dropped, only set
+    /// when the cell is built with `keep_alive`.
+    _keep_alive: Option<Arc<Shared<T>>>,
+
+    /// When set, stored values equal to the current one do not notify
+    /// watchers.
+    dedup: Option<fn(&T, &T) -> bool>,
 }
 
 /// Borrowed reference
@@ -152,7 +214,13 @@ pub struct Store<T> {
 /// [`Watch::borrow`]: struct.Watch.html#method.borrow
 #[derive(Debug)]
 pub struct Ref<'a, T: 'a> {
-    inner: RwLockReadGuard<'a, T>,
+    inner: RefInner<'a, T>,
+}
+
+#[derive(Debug)]
+enum RefInner<'a, T: 'a> {
+    Locked(RwLockReadGuard<'a, T>),
+    Snapshot(T),
 }
 
 /// Errors produced by `Watch`.
@@ -164,13 +232,15 @@ pub struct WatchError {
 /// Errors produced by `Store`.
 #[derive(Debug)]
 pub struct StoreError<T> {
+    // Only read by the `Debug` implementation
+    #[allow(dead_code)]
     inner: T,
 }
 
 #[derive(Debug)]
 struct Shared<T> {
     /// The most recent value
-    value: RwLock<T>,
+    value: Value<T>,
 
     /// The current version
     ///
@@ -185,6 +255,15 @@ struct Shared<T> {
     cancel: AtomicTask,
 }
 
+#[derive(Debug)]
+enum Value<T> {
+    /// Borrowed in place by watchers, which block the producer.
+    Locked(RwLock<T>),
+
+    /// Reference counted snapshots, atomically swapped by the producer.
+    Snapshot(Snapshot<T>),
+}
+
 #[derive(Debug)]
 struct Watchers {
     next_id: u64,
@@ -216,34 +295,69 @@ impl<T> Watch<T> {
     /// assert_eq!(*watch.borrow(), "goodbye");
     /// ```
     pub fn new(init: T) -> (Watch<T>, Store<T>) {
+        Watch::builder().build(init)
+    }
+
+    /// Create a new watch cell that only notifies watchers when the stored
+    /// value changes.
+    ///
+    /// Values stored by the `Store`, including through its `Sink`
+    /// implementation, are compared with the current value. When they are
+    /// equal, the value is still replaced but the version is not bumped and
+    /// watchers are not notified.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use futures_watch::*;
+    /// let (watch, mut store) = Watch::new_dedup("hello");
+    /// store.store("hello").unwrap();
+    /// # drop(watch);
+    /// ```
+    pub fn new_dedup(init: T) -> (Watch<T>, Store<T>)
+    where T: PartialEq,
+    {
+        Watch::builder().dedup().build(init)
+    }
+
+    /// Returns a builder that can be used to configure a new watch cell.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use futures_watch::*;
+    /// let (watch, store) = Watch::builder()
+    ///     .keep_alive(true)
+    ///     .build("hello");
+    ///
+    /// // The cell outlives its watchers
+    /// drop(watch);
+    /// assert_eq!(*store.subscribe().unwrap().borrow(), "hello");
+    /// ```
+    pub fn builder() -> Builder<T> {
+        Builder::new()
+    }
+
+    /// Create a new watcher of `shared`, having observed version `ver`.
+    fn from_shared(shared: Arc<Shared<T>>, ver: usize) -> Watch<T> {
         let inner = Arc::new(WatchInner::new());
 
-        // Insert the watcher
-        let mut watchers = FnvHashMap::with_capacity_and_hasher(0, Default::default());
-        watchers.insert(0, inner.clone());
-
-        let shared = Arc::new(Shared {
-            value: RwLock::new(init),
-            version: AtomicUsize::new(0),
-            watchers: Mutex::new(Watchers {
-                next_id: 1,
-                watchers,
-            }),
-            cancel: AtomicTask::new(),
-        });
+        let id = {
+            let mut watchers = shared.watchers.lock().unwrap();
+            let id = watchers.next_id;
+
+            watchers.next_id += 1;
+            watchers.watchers.insert(id, inner.clone());
 
-        let store = Store {
-            shared: Arc::downgrade(&shared),
+            id
         };
 
-        let watch = Watch {
+        Watch {
             shared,
             inner,
-            id: 0,
-            ver: 0,
-        };
-
-        (watch, store)
+            id,
+            ver,
+        }
     }
 
     /// Returns true if the current value represents the final value
@@ -271,6 +385,9 @@ impl<T> Watch<T> {
     /// long lived borrows could cause the produce half to block. It is
     /// recommended to keep the borrow as short lived as possible.
     ///
+    /// When the cell is built with [`Builder::snapshot`], the borrow holds a
+    /// snapshot of the value instead and never blocks the producer.
+    ///
     /// # Examples
     ///
     /// ```
@@ -278,17 +395,120 @@ impl<T> Watch<T> {
     /// let (watch, _) = Watch::new("hello");
     /// assert_eq!(*watch.borrow(), "hello");
     /// ```
-    pub fn borrow(&self) -> Ref<T> {
-        let inner = self.shared.value.read().unwrap();
+    ///
+    /// [`Builder::snapshot`]: struct.Builder.html#method.snapshot
+    pub fn borrow(&self) -> Ref<'_, T> {
+        let inner = match self.shared.value {
+            Value::Locked(ref lock) => RefInner::Locked(lock.read().unwrap()),
+            Value::Snapshot(ref snapshot) => RefInner::Snapshot(snapshot.load()),
+        };
+
         Ref { inner }
     }
 
+    /// Convert this watch into a stream of values.
+    ///
+    /// The stream first yields the current value, then each new value stored
+    /// in the cell. As with the `Watch` stream, intermediate values stored
+    /// between two polls are skipped. The stream ends once the `Store` is
+    /// dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate futures;
+    /// # extern crate futures_watch;
+    /// # pub fn main() {
+    /// # use futures::*;
+    /// # use futures_watch::*;
+    /// let (watch, mut store) = Watch::new("hello");
+    /// let values = watch.values();
+    ///
+    /// store.store("goodbye").unwrap();
+    /// drop(store);
+    ///
+    /// assert_eq!(values.collect().wait().unwrap(), ["goodbye"]);
+    /// # }
+    /// ```
+    pub fn values(self) -> Values<T>
+    where T: Clone,
+    {
+        Values::new(self)
+    }
+
+    /// Convert this watch into a stream yielding `f` applied to each new value.
+    ///
+    /// Unlike [`values`], the current value is not yielded: `f` is only called
+    /// when the value changes, with the value borrowed from the cell. The
+    /// stream ends once the `Store` is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate futures;
+    /// # extern crate futures_watch;
+    /// # pub fn main() {
+    /// # use futures::*;
+    /// # use futures_watch::*;
+    /// let (watch, mut store) = Watch::new(vec![1]);
+    /// let lens = watch.map(|v| v.len());
+    ///
+    /// store.store(vec![1, 2]).unwrap();
+    ///
+    /// let (len, _) = lens.into_future().wait().ok().unwrap();
+    /// assert_eq!(len, Some(2));
+    /// # }
+    /// ```
+    ///
+    /// [`values`]: #method.values
+    pub fn map<F, U>(self, f: F) -> Map<T, F>
+    where F: FnMut(&T) -> U,
+    {
+        Map::new(self, f)
+    }
+
     /// Convert this watch into a stream of values produced by an `M`-typed map function.
     pub fn then_stream<M: Then<T>>(self, then: M) -> then_stream::ThenStream<T, M> {
         then_stream::ThenStream::new(self, then)
     }
 }
 
+impl<T> Watch<Arc<T>> {
+    /// Returns an owned snapshot of the inner value.
+    ///
+    /// Unlike [`borrow`], the returned value does not borrow the watcher and
+    /// can be held for as long as needed. When the cell is built with
+    /// [`Builder::snapshot`], loading never blocks and holding the snapshot
+    /// never blocks the producer. Otherwise, the read lock is only held while
+    /// cloning the `Arc`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use futures_watch::*;
+    /// use std::sync::Arc;
+    ///
+    /// let (watch, mut store) = Watch::builder()
+    ///     .snapshot()
+    ///     .build(Arc::new("hello"));
+    ///
+    /// let snapshot = watch.load();
+    /// store.store(Arc::new("goodbye")).unwrap();
+    ///
+    /// assert_eq!(*snapshot, "hello");
+    /// assert_eq!(*watch.load(), "goodbye");
+    /// ```
+    ///
+    /// [`borrow`]: #method.borrow
+    /// [`Builder::snapshot`]: struct.Builder.html#method.snapshot
+    pub fn load(&self) -> Arc<T> {
+        match self.shared.value {
+            Value::Locked(ref lock) => lock.read().unwrap().clone(),
+            Value::Snapshot(ref snapshot) => snapshot.load(),
+        }
+    }
+}
+
 /// A stream of inner value change events.
 ///
 /// Whenever the inner value of the cell is updated by the `Store` handle, `()`
@@ -321,27 +541,7 @@ impl<T> Stream for Watch<T> {
 
 impl<T> Clone for Watch<T> {
     fn clone(&self) -> Self {
-        let inner = Arc::new(WatchInner::new());
-        let shared = self.shared.clone();
-
-        let id = {
-            let mut watchers = shared.watchers.lock().unwrap();
-            let id = watchers.next_id;
-
-            watchers.next_id += 1;
-            watchers.watchers.insert(id, inner.clone());
-
-            id
-        };
-
-        let ver = self.ver;
-
-        Watch {
-            shared: shared,
-            inner,
-            id,
-            ver,
-        }
+        Watch::from_shared(self.shared.clone(), self.ver)
     }
 }
 
@@ -373,6 +573,35 @@ impl<T> Store<T> {
     /// assert_eq!(*watch.borrow(), "goodbye");
     /// ```
     pub fn store(&mut self, value: T) -> Result<T, StoreError<T>> {
+        let dedup = self.dedup;
+        self.store_with(value, dedup)
+    }
+
+    /// Store a new value in the cell, notifying all watchers only if it
+    /// differs from the current value. The previous value is returned.
+    ///
+    /// This applies to a single call, see [`Watch::new_dedup`] to deduplicate
+    /// all stored values.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use futures_watch::*;
+    /// let (watch, mut store) = Watch::new("hello");
+    /// assert_eq!(store.store_if_changed("hello").unwrap(), "hello");
+    /// # drop(watch);
+    /// ```
+    ///
+    /// [`Watch::new_dedup`]: struct.Watch.html#method.new_dedup
+    pub fn store_if_changed(&mut self, value: T) -> Result<T, StoreError<T>>
+    where T: PartialEq,
+    {
+        self.store_with(value, Some(T::eq))
+    }
+
+    fn store_with(&mut self, value: T, dedup: Option<fn(&T, &T) -> bool>)
+        -> Result<T, StoreError<T>>
+    {
         let shared = match self.shared.upgrade() {
             Some(shared) => shared,
             // All `Watch` handles have been canceled
@@ -380,25 +609,133 @@ impl<T> Store<T> {
         };
 
         // Replace the value
-        let value = {
-            let mut lock = shared.value.write().unwrap();
-            mem::replace(&mut *lock, value)
-        };
+        let (value, modified) = shared.value.replace(value, dedup);
 
-        // Update the version. 2 is used so that the CLOSED bit is not set.
-        shared.version.fetch_add(2, SeqCst);
-
-        // Notify all watchers
-        notify_all(&*shared);
+        if modified {
+            changed(&*shared);
+        }
 
         // Return the old value
         Ok(value)
     }
 
+    /// Update the value in place, notifying all watchers.
+    ///
+    /// `f` is called with a mutable reference to the value while holding the
+    /// write lock, which avoids building a new value for small changes. The
+    /// version is bumped once, regardless of how much `f` changes. If all
+    /// watchers have been dropped, `f` is not called and an error is returned.
+    ///
+    /// When the cell is built with [`Builder::snapshot`], `f` is called with a
+    /// new reference to the current snapshot, which replaces it afterwards.
+    /// Watchers holding the previous snapshot are not affected: use
+    /// `Arc::make_mut` to modify a copy of the value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use futures_watch::*;
+    /// let (watch, mut store) = Watch::new(vec![1]);
+    /// store.update(|v| v.push(2)).unwrap();
+    /// assert_eq!(*watch.borrow(), [1, 2]);
+    /// ```
+    ///
+    /// [`Builder::snapshot`]: struct.Builder.html#method.snapshot
+    pub fn update<F>(&mut self, f: F) -> Result<(), StoreError<()>>
+    where F: FnOnce(&mut T),
+    {
+        let shared = match self.shared.upgrade() {
+            Some(shared) => shared,
+            // All `Watch` handles have been canceled
+            None => return Err(StoreError::new(())),
+        };
+
+        shared.value.update(|value| {
+            f(value);
+            true
+        });
+
+        changed(&*shared);
+
+        Ok(())
+    }
+
+    /// Update the value in place, notifying watchers only if `f` returns
+    /// `true`.
+    ///
+    /// This behaves like [`update`], but `f` reports whether it actually
+    /// changed the value. When it returns `false`, the version is left
+    /// unchanged and watchers are not notified. Returns the value returned by
+    /// `f`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use futures_watch::*;
+    /// let (watch, mut store) = Watch::new(1);
+    ///
+    /// // Setting the same value does not notify watchers
+    /// let set = |v: &mut i32| {
+    ///     let changed = *v != 2;
+    ///     *v = 2;
+    ///     changed
+    /// };
+    ///
+    /// assert!(store.update_if(set).unwrap());
+    /// assert!(!store.update_if(set).unwrap());
+    /// assert_eq!(*watch.borrow(), 2);
+    /// ```
+    ///
+    /// [`update`]: #method.update
+    pub fn update_if<F>(&mut self, f: F) -> Result<bool, StoreError<()>>
+    where F: FnOnce(&mut T) -> bool,
+    {
+        let shared = match self.shared.upgrade() {
+            Some(shared) => shared,
+            // All `Watch` handles have been canceled
+            None => return Err(StoreError::new(())),
+        };
+
+        let modified = shared.value.update(f);
+
+        if modified {
+            changed(&*shared);
+        }
+
+        Ok(modified)
+    }
+
+    /// Returns a new `Watch` handle to the cell.
+    ///
+    /// The new watcher starts out having observed the current value. Returns
+    /// `None` if the cell has been canceled because all watchers were dropped,
+    /// which cannot happen when the cell is built with `keep_alive`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use futures_watch::*;
+    /// let (watch, mut store) = Watch::new("hello");
+    /// store.store("goodbye").unwrap();
+    ///
+    /// let other = store.subscribe().unwrap();
+    /// assert_eq!(*other.borrow(), "goodbye");
+    /// # drop(watch);
+    /// ```
+    pub fn subscribe(&self) -> Option<Watch<T>> {
+        let shared = self.shared.upgrade()?;
+        let ver = shared.version.load(SeqCst);
+
+        Some(Watch::from_shared(shared, ver))
+    }
+
     /// Returns `Ready` when all watchers have dropped.
     ///
     /// This allows the producer to get notified when interest in the produced
-    /// values is canceled and immediately stop doing work.
+    /// values is canceled and immediately stop doing work. When the cell is
+    /// built with `keep_alive`, it is never canceled and this never returns
+    /// `Ready`.
+    #[allow(clippy::result_unit_err)]
     pub fn poll_cancel(&mut self) -> Poll<(), ()> {
         match self.shared.upgrade() {
             Some(shared) => {
@@ -426,6 +763,15 @@ impl<T> Sink for Store<T> {
     }
 }
 
+/// Bump the version and notify all watchers of a change
+fn changed<T>(shared: &Shared<T>) {
+    // Update the version. 2 is used so that the CLOSED bit is not set.
+    shared.version.fetch_add(2, SeqCst);
+
+    // Notify all watchers
+    notify_all(shared);
+}
+
 /// Notify all watchers of a change
 fn notify_all<T>(shared: &Shared<T>) {
     let watchers = shared.watchers.lock().unwrap();
@@ -451,7 +797,54 @@ impl<'a, T: 'a> ops::Deref for Ref<'a, T> {
     type Target = T;
 
     fn deref(&self) -> &T {
-        self.inner.deref()
+        match self.inner {
+            RefInner::Locked(ref guard) => guard,
+            RefInner::Snapshot(ref value) => value,
+        }
+    }
+}
+
+// ===== impl Value =====
+
+impl<T> Value<T> {
+    /// Replace the value, returning the previous one and whether it differs
+    /// from the new one according to `dedup`.
+    fn replace(&self, value: T, dedup: Option<fn(&T, &T) -> bool>) -> (T, bool) {
+        match *self {
+            Value::Locked(ref lock) => {
+                let mut lock = lock.write().unwrap();
+                let modified = dedup.map_or(true, |eq| !eq(&*lock, &value));
+
+                (mem::replace(&mut *lock, value), modified)
+            }
+            Value::Snapshot(ref snapshot) => {
+                let modified = dedup.map_or(true, |eq| !eq(&snapshot.load(), &value));
+
+                (snapshot.swap(value), modified)
+            }
+        }
+    }
+
+    /// Update the value with `f`, returning whether it was modified.
+    fn update<F>(&self, f: F) -> bool
+    where F: FnOnce(&mut T) -> bool,
+    {
+        match *self {
+            Value::Locked(ref lock) => {
+                let mut lock = lock.write().unwrap();
+                f(&mut *lock)
+            }
+            Value::Snapshot(ref snapshot) => {
+                let mut value = snapshot.load();
+                let modified = f(&mut value);
+
+                if modified {
+                    snapshot.swap(value);
+                }
+
+                modified
+            }
+        }
     }
 }
 
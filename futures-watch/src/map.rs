@@ -0,0 +1,110 @@
+use futures::{Async, Poll, Stream};
+
+use {Watch, WatchError};
+
+use std::fmt;
+use std::sync::atomic::Ordering::SeqCst;
+
+/// Stream yielding the current value of a `Watch`, then each new value.
+///
+/// Returned by [`Watch::values`].
+///
+/// [`Watch::values`]: struct.Watch.html#method.values
+#[derive(Debug)]
+pub struct Values<T> {
+    watch: Watch<T>,
+
+    /// `true` until the current value has been yielded.
+    first: bool,
+}
+
+/// Stream yielding the result of a function applied to each new value of a
+/// `Watch`.
+///
+/// Returned by [`Watch::map`].
+///
+/// [`Watch::map`]: struct.Watch.html#method.map
+pub struct Map<T, F> {
+    watch: Watch<T>,
+    f: F,
+}
+
+// ===== impl Values =====
+
+impl<T> Values<T> {
+    pub(crate) fn new(watch: Watch<T>) -> Values<T> {
+        Values {
+            watch,
+            first: true,
+        }
+    }
+}
+
+impl<T: Clone> Stream for Values<T> {
+    type Item = T;
+    type Error = WatchError;
+
+    fn poll(&mut self) -> Poll<Option<T>, WatchError> {
+        if self.first {
+            self.first = false;
+
+            // The version is loaded before the value, so that a value stored
+            // in between is yielded again rather than missed.
+            self.watch.ver = self.watch.shared.version.load(SeqCst);
+
+            return Ok(Async::Ready(Some(self.watch.borrow().clone())));
+        }
+
+        match self.watch.poll()? {
+            Async::Ready(Some(())) => Ok(Async::Ready(Some(self.watch.borrow().clone()))),
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl<T> Clone for Values<T> {
+    fn clone(&self) -> Values<T> {
+        Values {
+            watch: self.watch.clone(),
+            first: self.first,
+        }
+    }
+}
+
+// ===== impl Map =====
+
+impl<T, F> Map<T, F> {
+    pub(crate) fn new(watch: Watch<T>, f: F) -> Map<T, F> {
+        Map { watch, f }
+    }
+}
+
+impl<T, F, U> Stream for Map<T, F>
+where F: FnMut(&T) -> U,
+{
+    type Item = U;
+    type Error = WatchError;
+
+    fn poll(&mut self) -> Poll<Option<U>, WatchError> {
+        match self.watch.poll()? {
+            Async::Ready(Some(())) => Ok(Async::Ready(Some((self.f)(&self.watch.borrow())))),
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl<T, F: Clone> Clone for Map<T, F> {
+    fn clone(&self) -> Map<T, F> {
+        Map::new(self.watch.clone(), self.f.clone())
+    }
+}
+
+impl<T: fmt::Debug, F> fmt::Debug for Map<T, F> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Map")
+            .field("watch", &self.watch)
+            .finish()
+    }
+}
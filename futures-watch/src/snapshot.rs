@@ -0,0 +1,165 @@
+//! Lock-free storage for cells built with `Builder::snapshot`.
+//!
+//! The value is an `Arc`, stored as the raw pointer returned by
+//! `Arc::into_raw`. Watchers load the pointer and take a new reference to it,
+//! and the producer swaps in a new pointer. Neither side ever waits for the
+//! other.
+//!
+//! The only hazard is a watcher that loaded the pointer but has not taken its
+//! reference yet when the producer replaces the value: the producer must not
+//! release the replaced value from under it. The producer keeps a reference to
+//! each replaced value, tagged with the generation in which it was replaced,
+//! until no watcher can still be loading it.
+//!
+//! Watchers announce loads in progress in one of two `loading` counters,
+//! selected by the parity of the generation in which the load starts. When a
+//! store finds the counter of the previous generation drained, it starts a
+//! new generation and releases every value replaced before the one that just
+//! ended. Watchers that keep loading only hold back the values replaced in the
+//! last generation, so the retired values do not pile up under continuous
+//! loads.
+
+use std::fmt;
+use std::mem::ManuallyDrop;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicPtr, AtomicUsize};
+use std::sync::atomic::Ordering::SeqCst;
+
+pub(crate) struct Snapshot<T> {
+    /// The current value, converted with `into_raw`.
+    ptr: AtomicPtr<()>,
+
+    /// Current generation, only incremented by the producer.
+    generation: AtomicUsize,
+
+    /// Number of watchers currently loading the value, indexed by the parity
+    /// of the generation in which they started loading.
+    loading: [AtomicUsize; 2],
+
+    /// References to replaced values that watchers may still be loading,
+    /// along with the generation in which they were replaced. Only the
+    /// producer accesses it.
+    retired: Mutex<Vec<(usize, T)>>,
+
+    vtable: Vtable<T>,
+}
+
+/// Conversions between a reference counted `T` and a raw pointer.
+pub(crate) struct Vtable<T> {
+    into_raw: fn(T) -> *mut (),
+    from_raw: unsafe fn(*mut ()) -> T,
+    clone: fn(&T) -> T,
+}
+
+impl<T> Snapshot<T> {
+    pub fn new(value: T, vtable: Vtable<T>) -> Snapshot<T> {
+        Snapshot {
+            ptr: AtomicPtr::new((vtable.into_raw)(value)),
+            generation: AtomicUsize::new(0),
+            loading: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            retired: Mutex::new(Vec::new()),
+            vtable,
+        }
+    }
+
+    /// Returns a new reference to the current value.
+    pub fn load(&self) -> T {
+        let loading = loop {
+            let generation = self.generation.load(SeqCst);
+            let loading = &self.loading[generation & 1];
+
+            loading.fetch_add(1, SeqCst);
+
+            // The load is only accounted for in `generation` if it is still
+            // the current one, otherwise the producer may already have checked
+            // the counter.
+            if self.generation.load(SeqCst) == generation {
+                break loading;
+            }
+
+            loading.fetch_sub(1, SeqCst);
+        };
+
+        // The producer checks `loading` after replacing the pointer, so it
+        // either sees this load in progress or this load sees the new pointer.
+        let ptr = self.ptr.load(SeqCst);
+        let value = unsafe { self.clone_raw(ptr) };
+
+        loading.fetch_sub(1, SeqCst);
+        value
+    }
+
+    /// Replace the current value, returning the previous one. Must only be
+    /// called by the producer.
+    pub fn swap(&self, value: T) -> T {
+        let new = (self.vtable.into_raw)(value);
+        let old = self.ptr.swap(new, SeqCst);
+
+        let released: Vec<_> = {
+            let mut retired = self.retired.lock().unwrap();
+            let generation = self.generation.load(SeqCst);
+
+            if self.loading[0].load(SeqCst) == 0 && self.loading[1].load(SeqCst) == 0 {
+                // No watcher can still be loading any of the replaced values.
+                // They are dropped once the lock is released.
+                retired.drain(..).collect()
+            } else {
+                retired.push((generation, unsafe { self.clone_raw(old) }));
+
+                // Loads that started in the previous generation are done, and
+                // loads that started since can only have seen values replaced
+                // in the current generation.
+                if self.loading[(generation + 1) & 1].load(SeqCst) == 0 {
+                    self.generation.store(generation + 1, SeqCst);
+
+                    let n = retired.iter()
+                        .take_while(|&&(retired_in, _)| retired_in < generation)
+                        .count();
+
+                    retired.drain(..n).collect()
+                } else {
+                    vec![]
+                }
+            }
+        };
+
+        drop(released);
+
+        unsafe { (self.vtable.from_raw)(old) }
+    }
+
+    /// Take a new reference to the value pointed to by `ptr`, which must be
+    /// kept alive by the caller.
+    unsafe fn clone_raw(&self, ptr: *mut ()) -> T {
+        let value = ManuallyDrop::new((self.vtable.from_raw)(ptr));
+        (self.vtable.clone)(&value)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Snapshot<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Snapshot")
+            .field("value", &self.load())
+            .finish()
+    }
+}
+
+impl<T> Drop for Snapshot<T> {
+    fn drop(&mut self) {
+        unsafe { drop((self.vtable.from_raw)(*self.ptr.get_mut())) };
+    }
+}
+
+impl<T> Vtable<Arc<T>> {
+    pub fn arc() -> Vtable<Arc<T>> {
+        Vtable {
+            into_raw: |arc| Arc::into_raw(arc) as *mut (),
+            from_raw: arc_from_raw::<T>,
+            clone: Arc::clone,
+        }
+    }
+}
+
+unsafe fn arc_from_raw<T>(ptr: *mut ()) -> Arc<T> {
+    Arc::from_raw(ptr as *const T)
+}
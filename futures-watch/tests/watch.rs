@@ -2,10 +2,15 @@ extern crate futures;
 extern crate futures_test;
 extern crate futures_watch;
 
-use futures::{Stream};
+use futures::{Async, Sink, Stream};
 use futures_test::Harness;
 use futures_watch::*;
 
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::thread;
+
 #[test]
 fn smoke() {
     let (mut watch, mut store) = Watch::new("one");
@@ -63,3 +68,257 @@ fn multiple_watches() {
     assert_eq!(*watch1.borrow(), "two");
     assert_eq!(*watch2.borrow(), "two");
 }
+
+#[test]
+fn update_in_place() {
+    let (mut watch, mut store) = Watch::new(vec![1]);
+
+    Harness::poll_fn(|| watch.poll()).with(|harness| {
+        assert!(!harness.poll().unwrap().is_ready());
+
+        store.update(|v| v.push(2)).unwrap();
+
+        // The watch was notified
+        assert!(harness.poll().unwrap().is_ready());
+        assert!(!harness.poll().unwrap().is_ready());
+
+        // Unchanged values do not notify
+        assert!(!store.update_if(|v| v.len() > 3).unwrap());
+        assert!(!harness.poll().unwrap().is_ready());
+
+        assert!(store.update_if(|v| { v.push(3); true }).unwrap());
+        assert!(harness.poll().unwrap().is_ready());
+    });
+
+    assert_eq!(*watch.borrow(), [1, 2, 3]);
+
+    // Updating fails once all watches are dropped
+    drop(watch);
+    assert!(store.update(|v| v.clear()).is_err());
+}
+
+#[test]
+fn dedup_equal_values() {
+    let (mut watch, mut store) = Watch::new("one");
+
+    Harness::poll_fn(|| watch.poll()).with(|harness| {
+        assert!(!harness.poll().unwrap().is_ready());
+
+        // Storing an equal value does not notify
+        assert_eq!(store.store_if_changed("one").unwrap(), "one");
+        assert!(!harness.poll().unwrap().is_ready());
+
+        assert_eq!(store.store_if_changed("two").unwrap(), "one");
+        assert!(harness.poll().unwrap().is_ready());
+    });
+
+    let (mut watch, mut store) = Watch::new_dedup("one");
+
+    Harness::poll_fn(|| watch.poll()).with(|harness| {
+        assert!(!harness.poll().unwrap().is_ready());
+
+        // Both `store` and the `Sink` implementation are deduplicated
+        store.store("one").unwrap();
+        assert!(store.start_send("one").unwrap().is_ready());
+        assert!(!harness.poll().unwrap().is_ready());
+
+        assert!(store.start_send("two").unwrap().is_ready());
+        assert!(harness.poll().unwrap().is_ready());
+    });
+
+    assert_eq!(*watch.borrow(), "two");
+}
+
+#[test]
+fn subscribe_from_store() {
+    let (watch, mut store) = Watch::new("one");
+
+    let mut sub = store.subscribe().unwrap();
+    assert_eq!(*sub.borrow(), "one");
+
+    Harness::poll_fn(|| sub.poll()).with(|harness| {
+        assert!(!harness.poll().unwrap().is_ready());
+
+        store.store("two").unwrap();
+        assert!(harness.poll().unwrap().is_ready());
+    });
+
+    // Once all watchers are dropped, the cell is canceled
+    drop((watch, sub));
+    assert!(store.subscribe().is_none());
+    assert!(store.store("three").is_err());
+
+    Harness::poll_fn(|| store.poll_cancel()).with(|harness| {
+        assert!(harness.poll().unwrap().is_ready());
+    });
+}
+
+#[test]
+fn keep_alive_without_watchers() {
+    let (watch, mut store) = Watch::builder().keep_alive(true).build("one");
+    drop(watch);
+
+    // The cell is not canceled
+    Harness::poll_fn(|| store.poll_cancel()).with(|harness| {
+        assert!(!harness.poll().unwrap().is_ready());
+    });
+
+    assert_eq!(store.store("two").unwrap(), "one");
+
+    let mut watch = store.subscribe().unwrap();
+    assert_eq!(*watch.borrow(), "two");
+
+    Harness::poll_fn(|| watch.poll()).with(|harness| {
+        // The current value was already observed
+        assert!(!harness.poll().unwrap().is_ready());
+
+        drop(store);
+        assert!(harness.poll().unwrap().is_ready());
+    });
+
+    assert!(watch.is_final());
+}
+
+#[test]
+fn snapshot_does_not_block_store() {
+    let (watch, mut store) = Watch::builder()
+        .snapshot()
+        .build(Arc::new(vec![1]));
+
+    let mut watch2 = watch.clone();
+
+    // Holding on to the value does not prevent storing a new one
+    let borrowed = watch.borrow();
+    let loaded = watch.load();
+
+    Harness::poll_fn(|| watch2.poll()).with(|harness| {
+        assert!(!harness.poll().unwrap().is_ready());
+
+        let prev = store.store(Arc::new(vec![2])).unwrap();
+        assert!(Arc::ptr_eq(&prev, &loaded));
+
+        assert!(harness.poll().unwrap().is_ready());
+    });
+
+    assert_eq!(**borrowed, [1]);
+    assert_eq!(*loaded, [1]);
+    assert_eq!(*watch.load(), [2]);
+
+    // Updating replaces the snapshot
+    store.update(|v| Arc::make_mut(v).push(3)).unwrap();
+    assert!(!store.update_if(|_| false).unwrap());
+
+    assert_eq!(*loaded, [1]);
+    assert_eq!(*watch.load(), [2, 3]);
+}
+
+#[test]
+fn snapshot_concurrent_loads() {
+    let first = Arc::new(0);
+    let (watch, mut store) = Watch::builder()
+        .snapshot()
+        .build(first.clone());
+
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let readers: Vec<_> = (0..4).map(|_| {
+        let watch = watch.clone();
+        let stop = stop.clone();
+
+        thread::spawn(move || {
+            let mut last = 0;
+
+            while !stop.load(SeqCst) {
+                let value = *watch.load();
+                assert!(value >= last);
+                last = value;
+            }
+        })
+    }).collect();
+
+    for i in 1..10_000 {
+        store.store(Arc::new(i)).unwrap();
+    }
+
+    stop.store(true, SeqCst);
+
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    // Once no watcher is loading, storing releases all replaced values
+    store.store(Arc::new(10_000)).unwrap();
+
+    assert_eq!(Arc::strong_count(&first), 1);
+    assert_eq!(*watch.load(), 10_000);
+}
+
+#[test]
+fn values_and_map() {
+    let (watch, mut store) = Watch::new(1);
+    store.store(2).unwrap();
+
+    let mut values = watch.clone().values();
+    let mut doubled = watch.map(|v| v * 2);
+
+    Harness::poll_fn(|| values.poll()).with(|harness| {
+        // The current value is yielded first, and only once
+        assert_eq!(harness.poll().unwrap(), Async::Ready(Some(2)));
+        assert!(!harness.poll().unwrap().is_ready());
+
+        store.store(3).unwrap();
+        assert!(harness.is_notified());
+        assert_eq!(harness.poll().unwrap(), Async::Ready(Some(3)));
+    });
+
+    Harness::poll_fn(|| doubled.poll()).with(|harness| {
+        // Changes made before the first poll are still observed
+        assert_eq!(harness.poll().unwrap(), Async::Ready(Some(6)));
+        assert!(!harness.poll().unwrap().is_ready());
+
+        store.store(4).unwrap();
+        assert!(harness.is_notified());
+        assert_eq!(harness.poll().unwrap(), Async::Ready(Some(8)));
+
+        drop(store);
+        assert_eq!(harness.poll().unwrap(), Async::Ready(None));
+    });
+}
+
+#[test]
+fn snapshot_continuous_loads_release_values() {
+    let (watch, mut store) = Watch::builder()
+        .snapshot()
+        .build(Arc::new(0));
+
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let readers: Vec<_> = (0..4).map(|_| {
+        let watch = watch.clone();
+        let stop = stop.clone();
+
+        thread::spawn(move || {
+            while !stop.load(SeqCst) {
+                drop(watch.load());
+            }
+        })
+    }).collect();
+
+    let replaced: Vec<_> = (1..100_000).map(|i| {
+        let value = Arc::new(i);
+        let weak = Arc::downgrade(&value);
+
+        store.store(value).unwrap();
+        weak
+    }).collect();
+
+    // Watchers are still loading, which does not prevent releasing values
+    // replaced in earlier generations.
+    assert!(replaced[..50_000].iter().all(|weak| weak.upgrade().is_none()));
+
+    stop.store(true, SeqCst);
+
+    for reader in readers {
+        reader.join().unwrap();
+    }
+}